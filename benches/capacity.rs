@@ -0,0 +1,60 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BatchSize;
+use criterion::Criterion;
+use criterion::Throughput;
+use rust_decimal_macros::dec;
+use tokio_stream::Empty;
+
+use payengine::engine::PaymentsEngine;
+use payengine::engine::Tx;
+use payengine::engine::TxInner;
+
+const TX_COUNT: u64 = 1_000_000;
+const CLIENT_COUNT: u16 = 1000;
+
+fn txs() -> Vec<Tx> {
+    (1..=TX_COUNT)
+        .map(|tx_id| Tx {
+            client_id: (tx_id % u64::from(CLIENT_COUNT) + 1) as u16,
+            tx_id,
+            inner: TxInner::Deposit { amount: dec!(1.0) },
+        })
+        .collect()
+}
+
+fn run(mut engine: PaymentsEngine<Empty<Tx>>, txs: Vec<Tx>) {
+    engine.process_txs_batch(txs);
+}
+
+fn capacity(c: &mut Criterion) {
+    let input = txs();
+    let mut group = c.benchmark_group("capacity");
+    group.throughput(Throughput::Elements(TX_COUNT));
+    group.sample_size(10);
+    group.bench_function("default", |b| {
+        b.iter_batched(
+            || input.clone(),
+            |txs| run(PaymentsEngine::new(tokio_stream::empty()), txs),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("preallocated", |b| {
+        b.iter_batched(
+            || input.clone(),
+            |txs| {
+                let engine = PaymentsEngine::with_capacity(
+                    tokio_stream::empty(),
+                    CLIENT_COUNT.into(),
+                    TX_COUNT as usize,
+                );
+                run(engine, txs)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, capacity);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+use std::fs;
+use std::path::PathBuf;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use tokio::runtime::Runtime;
+
+use payengine::engine::PaymentsEngineBuilder;
+
+const TX_COUNT: u64 = 100_000;
+
+fn write_input() -> PathBuf {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 1..=TX_COUNT {
+        csv.push_str(&format!("deposit,{},{},1.0\n", tx % 1000 + 1, tx));
+    }
+    let path = std::env::temp_dir().join("payengine_channel_size_bench.csv");
+    fs::write(&path, csv).expect("failed to write bench input");
+    path
+}
+
+fn channel_size(c: &mut Criterion) {
+    let input = write_input();
+    let runtime = Runtime::new().expect("failed to start runtime");
+    let mut group = c.benchmark_group("channel_size");
+    group.throughput(Throughput::Elements(TX_COUNT));
+    group.sample_size(10);
+    for size in [100, 1000, 100_000] {
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let mut engine = PaymentsEngineBuilder::new()
+                        .channel_size(size)
+                        .build(tokio_stream::empty());
+                    engine
+                        .process_txs_from_file(&[&input])
+                        .await
+                        .expect("failed to process bench input");
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, channel_size);
+criterion_main!(benches);
@@ -0,0 +1,67 @@
+use std::fs;
+use std::path::PathBuf;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use criterion::Throughput;
+use tokio::runtime::Runtime;
+use tokio_stream::StreamExt;
+
+use payengine::reader::InputSource;
+#[cfg(feature = "mmap")]
+use payengine::reader::MmapCsvSource;
+
+const TX_COUNT: u64 = 1_000_000;
+
+fn write_input() -> (PathBuf, u64) {
+    let mut csv = String::from("type, client, tx, amount\n");
+    for tx in 1..=TX_COUNT {
+        csv.push_str(&format!("deposit, {}, {}, 1.2345\n", tx % 1000, tx));
+    }
+    let path = std::env::temp_dir().join("payengine_csv_parsing_bench.csv");
+    fs::write(&path, &csv).expect("failed to write bench input");
+    (path, csv.len() as u64)
+}
+
+fn csv_parsing(c: &mut Criterion) {
+    let (input, bytes) = write_input();
+    let runtime = Runtime::new().expect("failed to start runtime");
+    let mut group = c.benchmark_group("csv_parsing");
+    group.throughput(Throughput::Bytes(bytes));
+    group.sample_size(10);
+    group.bench_function("parse", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let parsed = InputSource::detect(&input)
+                    .into_stream()
+                    .fold(0, |count, tx| {
+                        tx.expect("failed to parse bench input");
+                        count + 1
+                    })
+                    .await;
+                assert_eq!(parsed, TX_COUNT);
+            })
+        })
+    });
+    #[cfg(feature = "mmap")]
+    group.bench_function("parse_mmap", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let parsed = MmapCsvSource::new(&input)
+                    .expect("failed to map bench input")
+                    .into_stream()
+                    .fold(0, |count, tx| {
+                        tx.expect("failed to parse bench input");
+                        count + 1
+                    })
+                    .await;
+                assert_eq!(parsed, TX_COUNT);
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, csv_parsing);
+criterion_main!(benches);
@@ -0,0 +1,96 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BatchSize;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use rust_decimal_macros::dec;
+use tokio::runtime::Runtime;
+
+use payengine::engine::PaymentsEngine;
+use payengine::engine::Tx;
+use payengine::engine::TxInner;
+
+const TX_COUNT: u64 = 500_000;
+const CLIENT_COUNT: u64 = 1000;
+
+fn client_of(tx_id: u64) -> u16 {
+    (tx_id % CLIENT_COUNT + 1) as u16
+}
+
+/// Mostly deposits, with withdrawals, disputes, resolves and transfers
+/// between clients mixed in.
+fn txs() -> Vec<Tx> {
+    (1..=TX_COUNT)
+        .map(|tx_id| {
+            let inner = match tx_id % 10 {
+                3 | 7 => TxInner::Withdrawal { amount: dec!(0.5) },
+                9 => TxInner::Transfer {
+                    to_client_id: client_of(tx_id + 1),
+                    amount: dec!(0.25),
+                },
+                5 | 6 if tx_id > CLIENT_COUNT => {
+                    // Dispute, then resolve, a deposit from the round before
+                    let deposit_id = tx_id - CLIENT_COUNT - (tx_id % 10 - 4);
+                    return Tx {
+                        client_id: client_of(deposit_id),
+                        tx_id: deposit_id,
+                        inner: if tx_id % 10 == 5 {
+                            TxInner::Dispute
+                        } else {
+                            TxInner::Resolve
+                        },
+                    };
+                }
+                _ => TxInner::Deposit { amount: dec!(1.0) },
+            };
+            Tx {
+                client_id: client_of(tx_id),
+                tx_id,
+                inner,
+            }
+        })
+        .collect()
+}
+
+fn parallel(c: &mut Criterion) {
+    let input = txs();
+    let runtime = Runtime::new().expect("failed to start runtime");
+    let mut group = c.benchmark_group("parallel");
+    group.throughput(Throughput::Elements(TX_COUNT));
+    group.sample_size(10);
+    group.bench_function("sequential", |b| {
+        b.iter_batched(
+            || input.clone(),
+            |txs| {
+                runtime.block_on(async {
+                    PaymentsEngine::new(tokio_stream::iter(txs))
+                        .process_txs()
+                        .await
+                        .expect("failed to process bench input");
+                })
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    for shards in [2, 4, 8] {
+        group.bench_with_input(BenchmarkId::new("shards", shards), &shards, |b, &shards| {
+            b.iter_batched(
+                || input.clone(),
+                |txs| {
+                    runtime.block_on(async {
+                        PaymentsEngine::new(tokio_stream::iter(txs))
+                            .process_txs_parallel(shards)
+                            .await
+                            .expect("failed to process bench input");
+                    })
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parallel);
+criterion_main!(benches);
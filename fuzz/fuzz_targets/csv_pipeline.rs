@@ -0,0 +1,51 @@
+#![no_main]
+use std::io::Cursor;
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+use rust_decimal::Decimal;
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+
+use payengine::engine::PaymentsEngine;
+use payengine::reader::fetch_csv_data_from_reader;
+
+const HEADER: &[u8] = b"type,client,tx,amount\n";
+
+fuzz_target!(|data: &[u8]| {
+    let input = [HEADER, data].concat();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("failed to build runtime");
+    let report = runtime.block_on(async {
+        let (sender, receiver) = channel(64);
+        let mut engine = PaymentsEngine::new(ReceiverStream::new(receiver));
+        // Malformed input may be rejected with an error, but must never panic
+        let _ = tokio::join!(
+            fetch_csv_data_from_reader(Cursor::new(input), sender),
+            engine.process_txs()
+        );
+        let mut report = Vec::new();
+        engine
+            .write_report(&mut report)
+            .expect("failed to write report");
+        String::from_utf8(report).expect("report is not UTF-8")
+    });
+
+    for row in report.lines().skip(1) {
+        let amounts: Vec<Decimal> = row
+            .split(',')
+            .skip(1)
+            .take(3)
+            .map(|amount| Decimal::from_str(amount).expect("unparseable amount"))
+            .collect();
+        assert_eq!(amounts.len(), 3, "row without amounts: {}", row);
+        assert!(amounts[0] >= Decimal::from(0), "negative available: {}", row);
+        assert_eq!(
+            amounts[0] + amounts[1],
+            amounts[2],
+            "total mismatch: {}",
+            row
+        );
+    }
+});
@@ -0,0 +1,40 @@
+use chrono::Duration;
+use chrono::NaiveDate;
+
+use super::engine::Amount;
+use super::engine::ClientId;
+use super::engine::TxId;
+use super::fraud::FraudScore;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    CircuitBreakerTripped {
+        reason: String,
+    },
+    DailyVolumeCapReached {
+        date: NaiveDate,
+        volume: Amount,
+        limit: Amount,
+    },
+    HighChargebackRate {
+        client_id: ClientId,
+        rate: f64,
+        threshold: f64,
+    },
+    FraudScoreExceeded {
+        client_id: ClientId,
+        tx_id: TxId,
+        score: FraudScore,
+        threshold: FraudScore,
+    },
+    DisputeRateLimitExceeded {
+        client_id: ClientId,
+        disputes: u32,
+        window: Duration,
+    },
+    /// Only raised with the deadlock-detection feature
+    DeadlockDetected {
+        deadlocks: usize,
+        threads: usize,
+    },
+}
@@ -0,0 +1,105 @@
+use std::io::Write;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use super::engine::Amount;
+use super::engine::ClientId;
+use super::engine::IgnoreReason;
+use super::engine::Tx;
+use super::engine::TxId;
+use super::fx::Currency;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditEvent {
+    FxTransfer {
+        tx_id: TxId,
+        from_client: ClientId,
+        to_client: ClientId,
+        debited: Amount,
+        credited: Amount,
+        from_currency: Currency,
+        to_currency: Currency,
+        rate: Decimal,
+    },
+    /// A fee credited to the fee ledger. Kept out of the transaction
+    /// history, so it can't be disputed
+    FeeCredited {
+        tx_id: TxId,
+        from_client: ClientId,
+        fee_account: ClientId,
+        amount: Amount,
+    },
+    CurrencyConversion {
+        client_id: ClientId,
+        from_currency: Currency,
+        to_currency: Currency,
+        rate: Decimal,
+        available_before: Amount,
+        held_before: Amount,
+        available_after: Amount,
+        held_after: Amount,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditOutcome {
+    Accepted,
+    Rejected { reason: IgnoreReason },
+}
+
+/// Told what the engine decided for every transaction it is given.
+pub trait AuditSink: Send {
+    fn record(&mut self, tx: &Tx, outcome: AuditOutcome);
+}
+
+#[derive(Serialize)]
+struct AuditRow {
+    #[serde(rename = "type")]
+    tx_type: &'static str,
+    client: ClientId,
+    tx: TxId,
+    amount: Option<Amount>,
+    outcome: &'static str,
+    reason: &'static str,
+}
+
+/// Writes one CSV row per transaction, with a header before the first.
+pub struct CsvAuditSink<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> CsvAuditSink<W> {
+    pub fn new(writer: csv::Writer<W>) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> csv::Writer<W> {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> AuditSink for CsvAuditSink<W> {
+    fn record(&mut self, tx: &Tx, outcome: AuditOutcome) {
+        let (outcome, reason) = match &outcome {
+            AuditOutcome::Accepted => ("accepted", ""),
+            AuditOutcome::Rejected { reason } => ("rejected", reason.code()),
+        };
+        let row = AuditRow {
+            tx_type: tx.inner.type_name(),
+            client: tx.client_id,
+            tx: tx.tx_id,
+            amount: tx.inner.amount(),
+            outcome,
+            reason,
+        };
+        // Auditing must not stop transactions from being processed
+        if let Err(e) = self.writer.serialize(row) {
+            eprintln!("Error writing audit log: {}", e);
+            return;
+        }
+        if let Err(e) = self.writer.flush() {
+            eprintln!("Error writing audit log: {}", e)
+        }
+    }
+}
@@ -1,223 +1,4912 @@
+use std::any::Any;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
 use std::sync::Arc;
 
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::NaiveDate;
+use chrono::Utc;
 use parking_lot::Mutex;
 
+use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Empty;
+use tokio_stream::Stream;
 use tokio_stream::StreamExt;
 
+use super::alert::Alert;
+use super::audit::AuditEvent;
+use super::audit::AuditOutcome;
+use super::audit::AuditSink;
+use super::clock::Clock;
+use super::error::EngineError;
+use super::fraud::AccountContext;
+use super::fraud::FraudScore;
+use super::fraud::FraudScorer;
+use super::fx::Currency;
+use super::fx::ExchangeRateProvider;
+use super::health::CheckStatus;
+use super::health::HealthCheck;
+use super::health::HealthStatus;
+use super::reader::fetch_csv_data_blocking_with_options;
+use super::reader::fetch_csv_data_with_options;
+use super::reader::AmountPrecision;
+use super::reader::CsvOptions;
+use super::reader::InputSource;
+use super::report::report_writer;
+use super::report::CsvReportWriter;
+use super::report::ReportFormat;
+use super::report::ReportWriter;
+
 pub type ClientId = u16;
-pub type TxId = u32;
+pub type TxId = u64;
+/// Whether a transaction was applied, and if not, why.
+pub type TxResult = Result<(), EngineError>;
 pub type Amount = rust_decimal::Decimal;
 
-struct ClientAccount {
+type AlertHandler = Box<dyn Fn(&Alert) + Send + Sync>;
+
+pub const CHANNEL_SIZE: usize = 10000;
+
+const RECENT_DISPUTE_WINDOW_DAYS: i64 = 30;
+
+/// Never given an account, as it usually stands for a missing id
+pub const RESERVED_CLIENT_ID: ClientId = 0;
+
+#[cfg(feature = "deadlock-detection")]
+const DEADLOCK_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+const TX_HISTORY_WARN_SIZE: usize = 1_000_000;
+const LOCKED_ACCOUNT_WARN_RATIO: f64 = 0.05;
+const ERROR_RATE_WARN_RATIO: f64 = 0.01;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAccount {
     available: Amount,
     held: Amount,
     locked: bool,
+    currency: Currency,
+    total_deposited: Amount,
+    total_withdrawn: Amount,
+}
+
+impl Default for ClientAccount {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientAccount {
-    fn new() -> Self {
+    /// An empty, unlocked account in the default currency.
+    pub fn new() -> Self {
+        Self::with_currency(Currency::default())
+    }
+
+    fn with_currency(currency: Currency) -> Self {
         Self {
             locked: false,
             held: dec!(0.0),
             available: dec!(0.0),
+            currency,
+            total_deposited: dec!(0.0),
+            total_withdrawn: dec!(0.0),
         }
     }
 
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    pub fn total(&self) -> Amount {
+        self.available + self.held
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    /// Everything ever credited to the account, including transfers in and
+    /// interest.
+    pub fn total_deposited(&self) -> Amount {
+        self.total_deposited
+    }
+
+    /// Everything ever debited from the account, including transfers out
+    /// and fees. Chargebacks are not withdrawals and are not included.
+    pub fn total_withdrawn(&self) -> Amount {
+        self.total_withdrawn
+    }
+
+    pub fn net_flow(&self) -> Amount {
+        self.total_deposited - self.total_withdrawn
+    }
+
+    pub fn is_solvent(&self) -> bool {
+        self.available >= dec!(0) && self.held >= dec!(0)
+    }
+
+    // The lifetime totals only ever grow, so they overflow before the
+    // balance does
+    fn can_credit(&self, amount: Amount) -> bool {
+        self.total().checked_add(amount).is_some()
+            && self.total_deposited.checked_add(amount).is_some()
+    }
+
+    fn can_debit(&self, amount: Amount) -> bool {
+        self.total_withdrawn.checked_add(amount).is_some()
+    }
+
     fn deposit(&mut self, amount: Amount) {
         self.available += amount;
+        self.total_deposited += amount;
+        debug_assert!(self.is_solvent());
     }
 
     fn withdrawal(&mut self, amount: Amount) {
         self.available -= amount;
+        self.total_withdrawn += amount;
+        debug_assert!(self.is_solvent());
+    }
+
+    // Rolling a transaction back leaves the lifetime totals as though it
+    // had never happened
+    fn undo_deposit(&mut self, amount: Amount) {
+        self.available -= amount;
+        self.total_deposited -= amount;
+        debug_assert!(self.is_solvent());
+    }
+
+    fn undo_withdrawal(&mut self, amount: Amount) {
+        self.available += amount;
+        self.total_withdrawn -= amount;
+        debug_assert!(self.is_solvent());
     }
 
     fn dispute(&mut self, amount: Amount) {
         self.available -= amount;
         self.held += amount;
+        debug_assert!(self.is_solvent());
     }
 
     fn resolve(&mut self, amount: Amount) {
         self.available += amount;
         self.held -= amount;
+        debug_assert!(self.is_solvent());
     }
 
     fn chargeback(&mut self, amount: Amount) {
-        self.available -= amount;
         self.held -= amount;
         self.locked = true;
+        debug_assert!(self.is_solvent());
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// Formats the account's report columns, without the client id.
+impl fmt::Display for ClientAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.4},{:.4},{:.4},{}",
+            self.available,
+            self.held,
+            self.total(),
+            self.locked
+        )
+    }
+}
+
+#[derive(Default)]
+struct ClientActivity {
+    first_activity: Option<DateTime<Utc>>,
+    last_activity: Option<DateTime<Utc>>,
+    tx_count: u64,
+    deposit_count: u64,
+    deposited: Amount,
+    withdrawal_count: u64,
+    withdrawn: Amount,
+    dispute_times: VecDeque<DateTime<Utc>>,
+}
+
+impl ClientActivity {
+    fn record(&mut self, tx: &Tx, now: DateTime<Utc>) {
+        self.first_activity.get_or_insert(now);
+        self.last_activity = Some(now);
+        self.tx_count += 1;
+        match tx.inner {
+            TxInner::Deposit { amount } => {
+                self.deposit_count += 1;
+                self.deposited += amount;
+            }
+            TxInner::Withdrawal { amount } => {
+                self.withdrawal_count += 1;
+                self.withdrawn += amount;
+            }
+            TxInner::Dispute => self.dispute_times.push_back(now),
+            _ => {}
+        }
+        self.forget_old_disputes(now);
+    }
+
+    // Leaves first and last activity alone, as the client was still active
+    fn undo(&mut self, tx: &Tx) {
+        self.tx_count -= 1;
+        match tx.inner {
+            TxInner::Deposit { amount } => {
+                self.deposit_count -= 1;
+                self.deposited -= amount;
+            }
+            TxInner::Withdrawal { amount } => {
+                self.withdrawal_count -= 1;
+                self.withdrawn -= amount;
+            }
+            _ => {}
+        }
+    }
+
+    fn forget_old_disputes(&mut self, now: DateTime<Utc>) {
+        let window = Duration::days(RECENT_DISPUTE_WINDOW_DAYS);
+        while matches!(self.dispute_times.front(), Some(&t) if now - t > window) {
+            self.dispute_times.pop_front();
+        }
+    }
+
+    fn recent_dispute_count(&self, now: DateTime<Utc>) -> u32 {
+        let window = Duration::days(RECENT_DISPUTE_WINDOW_DAYS);
+        self.dispute_times
+            .iter()
+            .filter(|&&t| now - t <= window)
+            .count() as u32
+    }
+}
+
+fn average(total: Amount, count: u64) -> Amount {
+    if count == 0 {
+        dec!(0.0)
+    } else {
+        (total / Amount::from(count)).round_dp(4)
+    }
+}
+
+fn ratio(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+struct DailyVolumeLimit {
+    max_daily_volume: Amount,
+    day: Option<NaiveDate>,
+    volume: Amount,
+    halted: bool,
+}
+
+impl DailyVolumeLimit {
+    fn roll_over(&mut self, today: NaiveDate) {
+        if self.day != Some(today) {
+            self.day = Some(today);
+            self.volume = dec!(0.0);
+            self.halted = false;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Tx {
     pub client_id: ClientId,
     pub tx_id: TxId,
     pub inner: TxInner,
 }
 
-#[derive(Debug, PartialEq)]
+/// More kinds of transaction may be added, so matches outside this crate
+/// need a wildcard arm.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum TxInner {
-    Deposit { amount: Amount },
-    Withdrawal { amount: Amount },
+    Deposit {
+        amount: Amount,
+    },
+    Withdrawal {
+        amount: Amount,
+    },
     Dispute,
     Resolve,
     Chargeback,
+    Transfer {
+        to_client_id: ClientId,
+        amount: Amount,
+    },
+    /// An administrative deduction. Fees apply even to locked accounts and
+    /// cannot be disputed, so no dispute, resolve or chargeback may
+    /// reference a fee's tx id.
+    Fee {
+        amount: Amount,
+    },
+    FxTransfer {
+        to_client: ClientId,
+        amount: Amount,
+        from_currency: Currency,
+        to_currency: Currency,
+        rate: Decimal,
+    },
+}
+
+impl TxInner {
+    /// The name used for this kind of transaction in the input's type
+    /// column.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            TxInner::Deposit { .. } => "deposit",
+            TxInner::Withdrawal { .. } => "withdrawal",
+            TxInner::Dispute => "dispute",
+            TxInner::Resolve => "resolve",
+            TxInner::Chargeback => "chargeback",
+            TxInner::Transfer { .. } => "transfer",
+            TxInner::Fee { .. } => "fee",
+            TxInner::FxTransfer { .. } => "fx_transfer",
+        }
+    }
+
+    pub fn amount(&self) -> Option<Amount> {
+        match self {
+            TxInner::Deposit { amount }
+            | TxInner::Withdrawal { amount }
+            | TxInner::Transfer { amount, .. }
+            | TxInner::Fee { amount }
+            | TxInner::FxTransfer { amount, .. } => Some(*amount),
+            TxInner::Dispute | TxInner::Resolve | TxInner::Chargeback => None,
+        }
+    }
+
+    // Disputes, resolves and chargebacks carry the id of the transaction
+    // they refer to rather than an id of their own
+    fn references_prior_tx(&self) -> bool {
+        matches!(
+            self,
+            TxInner::Dispute | TxInner::Resolve | TxInner::Chargeback
+        )
+    }
+}
+
+impl fmt::Display for TxInner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxInner::Deposit { amount }
+            | TxInner::Withdrawal { amount }
+            | TxInner::Fee { amount } => write!(f, "{} of {:.4}", self.type_name(), amount),
+            TxInner::Dispute | TxInner::Resolve | TxInner::Chargeback => {
+                f.write_str(self.type_name())
+            }
+            TxInner::Transfer {
+                to_client_id,
+                amount,
+            } => write!(f, "transfer of {:.4} to client {}", amount, to_client_id),
+            TxInner::FxTransfer {
+                to_client,
+                amount,
+                from_currency,
+                to_currency,
+                rate,
+            } => write!(
+                f,
+                "fx_transfer of {:.4} {} to client {} as {} at {}",
+                amount, from_currency, to_client, to_currency, rate
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Tx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tx#{} (client {}): {}",
+            self.tx_id, self.client_id, self.inner
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IgnoreReason {
+    DailyVolumeCapReached,
+    InvalidAmount,
+    AccountLocked,
+    DuplicateTxId,
+    InsufficientFunds,
+    /// A balance, a lifetime total or the engine's total would grow
+    /// beyond the largest amount that can be held
+    BalanceOverflow,
+    InvalidRecipient,
+    VelocityExceeded,
+    FraudSuspect,
+    /// Broke `PaymentsEngineBuilder::max_balance`
+    MaxBalanceExceeded,
+    /// Broke `PaymentsEngineBuilder::min_deposit`
+    BelowMinimumDeposit,
+    /// Broke `PaymentsEngineBuilder::min_withdrawal`
+    BelowMinimumWithdrawal,
+    /// Client id 0 is reserved
+    InvalidClientId,
+    /// An fx transfer's currencies don't match the accounts it moves
+    /// money between
+    CurrencyMismatch,
+    /// An fx transfer's agreed rate is not positive, or strays too far
+    /// from the provider's rate
+    InvalidExchangeRate,
+    /// A dispute, resolve or chargeback names no deposit or withdrawal of
+    /// the client's
+    UnknownTx,
+    AlreadyDisputed,
+    NotDisputed,
+}
+
+impl IgnoreReason {
+    /// Stable name for logs and reports.
+    pub fn code(&self) -> &'static str {
+        match self {
+            IgnoreReason::DailyVolumeCapReached => "daily_volume_cap_reached",
+            IgnoreReason::InvalidAmount => "invalid_amount",
+            IgnoreReason::AccountLocked => "account_locked",
+            IgnoreReason::DuplicateTxId => "duplicate_tx_id",
+            IgnoreReason::InsufficientFunds => "insufficient_funds",
+            IgnoreReason::BalanceOverflow => "balance_overflow",
+            IgnoreReason::InvalidRecipient => "invalid_recipient",
+            IgnoreReason::VelocityExceeded => "velocity_exceeded",
+            IgnoreReason::FraudSuspect => "fraud_suspect",
+            IgnoreReason::MaxBalanceExceeded => "max_balance_exceeded",
+            IgnoreReason::BelowMinimumDeposit => "below_minimum_deposit",
+            IgnoreReason::BelowMinimumWithdrawal => "below_minimum_withdrawal",
+            IgnoreReason::InvalidClientId => "invalid_client_id",
+            IgnoreReason::CurrencyMismatch => "currency_mismatch",
+            IgnoreReason::InvalidExchangeRate => "invalid_exchange_rate",
+            IgnoreReason::UnknownTx => "unknown_tx",
+            IgnoreReason::AlreadyDisputed => "already_disputed",
+            IgnoreReason::NotDisputed => "not_disputed",
+        }
+    }
+}
+
+impl fmt::Display for IgnoreReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// What the engine did with a transaction it was given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxOutcome {
+    Applied,
+    Ignored(IgnoreReason),
+}
+
+/// Amounts serialize as strings so no precision is lost to floating point.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportRow {
+    #[serde(alias = "client")]
+    pub client_id: ClientId,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargebackRejection {
+    AccountNotFound,
+    AccountLocked,
+    TxNotFound,
+    WrongClient,
+    NotDisputed,
+}
+
+/// The state an account would be left in by a chargeback. If the
+/// chargeback would be ignored, `rejection` says why and the balances are
+/// the account's current ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationResult {
+    pub available: Amount,
+    pub held: Amount,
+    pub locked: bool,
+    pub rejection: Option<ChargebackRejection>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct FeePassSummary {
+    pub charged: usize,
+    pub skipped: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct EngineStatistics {
+    pub total_clients: usize,
+    pub total_transactions: u64,
+    pub total_disputed: usize,
+    pub total_locked_accounts: usize,
+    pub total_available: Amount,
+    pub total_held: Amount,
+    /// Accepted transactions of each type
+    pub deposit_count: u64,
+    pub withdrawal_count: u64,
+    pub dispute_count: u64,
+    pub resolve_count: u64,
+    pub chargeback_count: u64,
+    pub ignored_by_reason: HashMap<IgnoreReason, u64>,
+    /// Time since the engine was created
+    pub uptime: std::time::Duration,
+    /// Accepted and ignored transactions over the uptime
+    pub txs_per_second: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessingSummary {
+    pub transactions_accepted: u64,
+    pub transactions_ignored: u64,
+    pub clients: usize,
+    /// None until a transaction has gone through `update`
+    #[cfg(feature = "bench")]
+    pub update_timings: Option<UpdateTimings>,
+}
+
+/// Wall-clock time taken by each call to `update`.
+#[cfg(feature = "bench")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateTimings {
+    pub min: std::time::Duration,
+    pub max: std::time::Duration,
+    pub mean: std::time::Duration,
+    pub p99: std::time::Duration,
+}
+
+#[cfg(feature = "bench")]
+impl UpdateTimings {
+    fn from_histogram(histogram: &UpdateHistogram) -> Option<Self> {
+        if histogram.count == 0 {
+            return None;
+        }
+        // Nearest-rank percentile, rounded up to the end of its bucket
+        let p99_rank = (histogram.count * 99).div_ceil(100);
+        let mut seen = 0;
+        let p99_bucket = histogram
+            .buckets
+            .iter()
+            .position(|&count| {
+                seen += count;
+                seen >= p99_rank
+            })
+            .expect("histogram counts add up to its total");
+        let nanos = |nanos: u128| std::time::Duration::from_nanos(nanos as u64);
+        Some(UpdateTimings {
+            min: histogram.min,
+            max: histogram.max,
+            mean: nanos(histogram.total.as_nanos() / u128::from(histogram.count)),
+            p99: nanos(UpdateHistogram::bucket_end(p99_bucket).into()).min(histogram.max),
+        })
+    }
+}
+
+/// Durations of `update` calls, kept in a fixed number of buckets so that
+/// a long-running engine uses the same memory however many it has timed.
+/// Each power of two nanoseconds is split into eight buckets, so
+/// percentiles are within an eighth of the true value.
+#[cfg(feature = "bench")]
+#[derive(Clone)]
+struct UpdateHistogram {
+    count: u64,
+    total: std::time::Duration,
+    min: std::time::Duration,
+    max: std::time::Duration,
+    buckets: [u64; UpdateHistogram::BUCKETS],
+}
+
+#[cfg(feature = "bench")]
+impl Default for UpdateHistogram {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            total: std::time::Duration::ZERO,
+            min: std::time::Duration::MAX,
+            max: std::time::Duration::ZERO,
+            buckets: [0; UpdateHistogram::BUCKETS],
+        }
+    }
+}
+
+#[cfg(feature = "bench")]
+impl UpdateHistogram {
+    const BUCKETS: usize = 8 * 62;
+
+    fn record(&mut self, duration: std::time::Duration) {
+        self.count += 1;
+        self.total = self.total.saturating_add(duration);
+        self.min = self.min.min(duration);
+        self.max = self.max.max(duration);
+        let nanos = std::convert::TryFrom::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[Self::bucket(nanos)] += 1;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.total = self.total.saturating_add(other.total);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+    }
+
+    // Below 8ns every nanosecond has a bucket, above it the three bits
+    // after the leading one pick one of eight per power of two
+    fn bucket(nanos: u64) -> usize {
+        if nanos < 8 {
+            return nanos as usize;
+        }
+        let exponent = 63 - nanos.leading_zeros();
+        let eighth = (nanos >> (exponent - 3)) & 7;
+        (exponent as usize - 2) * 8 + eighth as usize
+    }
+
+    // The longest duration, in nanoseconds, that falls into `bucket`
+    fn bucket_end(bucket: usize) -> u64 {
+        if bucket < 8 {
+            return bucket as u64;
+        }
+        let shift = bucket / 8 - 1;
+        let start = (8 + bucket as u64 % 8) << shift;
+        start + ((1 << shift) - 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAccountSnapshot {
+    pub client_id: ClientId,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+    pub currency: Currency,
+}
+
+/// Work for one of the tasks behind `process_txs_parallel`.
+enum ShardMsg {
+    Tx(Tx),
+    /// Gives up the client's account, if the shard has one
+    Release(ClientId, oneshot::Sender<Option<Arc<Mutex<ClientAccount>>>>),
+    Adopt(ClientId, Arc<Mutex<ClientAccount>>),
+}
+
+async fn send_to_shard(shard: &Sender<ShardMsg>, msg: ShardMsg) -> anyhow::Result<()> {
+    shard
+        .send(msg)
+        .await
+        .map_err(|_| anyhow::anyhow!("a shard stopped processing"))
+}
+
+/// Moves `client_id`'s account, if there is one, from one shard to another.
+async fn move_account(
+    client_id: ClientId,
+    from: &Sender<ShardMsg>,
+    to: &Sender<ShardMsg>,
+) -> anyhow::Result<()> {
+    let (reply, account) = oneshot::channel();
+    send_to_shard(from, ShardMsg::Release(client_id, reply)).await?;
+    if let Some(account) = account.await? {
+        send_to_shard(to, ShardMsg::Adopt(client_id, account)).await?;
+    }
+    Ok(())
+}
+
+/// Runs the check on a thread of its own, which a deadlocked runtime can't
+/// starve, until the returned sender is dropped.
+#[cfg(feature = "deadlock-detection")]
+fn spawn_deadlock_detector(
+    alerts: Option<broadcast::Sender<Alert>>,
+) -> std::sync::mpsc::Sender<()> {
+    let (stop, stopped) = std::sync::mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        while let Err(std::sync::mpsc::RecvTimeoutError::Timeout) =
+            stopped.recv_timeout(DEADLOCK_CHECK_INTERVAL)
+        {
+            let deadlocks = parking_lot::deadlock::check_deadlock();
+            if deadlocks.is_empty() {
+                continue;
+            }
+            let threads = deadlocks.iter().map(Vec::len).sum();
+            eprintln!(
+                "{} deadlocks detected across {} threads",
+                deadlocks.len(),
+                threads
+            );
+            if let Some(sender) = &alerts {
+                // Sending only fails when nobody is subscribed
+                let _ = sender.send(Alert::DeadlockDetected {
+                    deadlocks: deadlocks.len(),
+                    threads,
+                });
+            }
+        }
+    });
+    stop
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "unknown cause"
+    }
+}
+
+/// The client other than the sender whose account `tx` credits.
+fn recipient(tx: &Tx) -> Option<ClientId> {
+    match tx.inner {
+        TxInner::Transfer { to_client_id, .. } => Some(to_client_id),
+        TxInner::FxTransfer { to_client, .. } => Some(to_client),
+        _ => None,
+    }
+}
+
+fn report_row(client_id: ClientId, account: &ClientAccount) -> ReportRow {
+    ReportRow {
+        client_id,
+        available: report_amount(account.available),
+        held: report_amount(account.held),
+        total: report_amount(account.total()),
+        locked: account.locked,
+    }
+}
+
+/// Up to four decimal places, and at least one, whatever scale the input
+/// amounts were written with.
+fn report_amount(amount: Amount) -> Amount {
+    let mut amount = amount.round_dp(4).normalize();
+    if amount.is_zero() {
+        amount.set_sign_positive(true);
+    }
+    if amount.scale() == 0 {
+        amount.rescale(1);
+    }
+    amount
+}
+
+/// The balances of one client's account, as kept in an `EngineState`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientAccountData {
+    pub available: Amount,
+    pub held: Amount,
+    pub locked: bool,
+    pub currency: Currency,
+    #[serde(default)]
+    pub total_deposited: Amount,
+    #[serde(default)]
+    pub total_withdrawn: Amount,
+}
+
+/// Everything needed to carry on processing where an engine left off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineState {
+    pub client_accounts: HashMap<ClientId, ClientAccountData>,
+    pub done_txs: HashMap<TxId, Tx>,
+    pub disputed_txs: Vec<TxId>,
+    #[serde(default)]
+    pub charged_back_txs: Vec<TxId>,
 }
 
 #[derive(Default)]
 pub struct PaymentsEngine<T> {
+    config: EngineConfig,
     client_accounts: HashMap<ClientId, Arc<Mutex<ClientAccount>>>,
+    // Sums over every account, kept up to date by each balance change
+    running_total_available: Amount,
+    running_total_held: Amount,
+    // When several are held, they are locked in the order declared here
     done_txs: Arc<Mutex<HashMap<TxId, Tx>>>,
     disputed_txs: Arc<Mutex<HashSet<TxId>>>,
+    // Never disputed again or rolled back
+    charged_back_txs: Arc<Mutex<HashSet<TxId>>>,
+    exchange_rates: Option<Arc<dyn ExchangeRateProvider>>,
+    audit_log: Vec<AuditEvent>,
+    client_activity: HashMap<ClientId, ClientActivity>,
+    fraud_scorer: Option<(Arc<dyn FraudScorer>, FraudScore)>,
+    ignored_txs: HashMap<IgnoreReason, u64>,
+    accepted_txs: u64,
+    accepted_by_type: HashMap<&'static str, u64>,
+    panic_count: u64,
+    started: Option<std::time::Instant>,
+    clock: Option<Arc<dyn Clock>>,
+    velocity_limit: Option<Amount>,
+    velocity_windows: HashMap<ClientId, VecDeque<(DateTime<Utc>, TxId, Amount)>>,
+    daily_volume: Option<DailyVolumeLimit>,
+    alert_handlers: Vec<AlertHandler>,
+    alert_channel: Option<broadcast::Sender<Alert>>,
+    fee_account: Option<ClientId>,
+    internal_tx_count: TxId,
+    report_format: ReportFormat,
+    audit_sink: Option<Box<dyn AuditSink>>,
+    // Synchronous, as apply_tx invalidates entries and is not async
+    #[cfg(feature = "cache")]
+    balance_cache: Option<moka::sync::Cache<ClientId, ClientAccountSnapshot>>,
+    #[cfg(feature = "bench")]
+    update_durations: UpdateHistogram,
+    #[cfg(feature = "deadlock-detection")]
+    deadlock_detector: bool,
     input_source: T,
 }
 
-impl<T: StreamExt<Item = Tx> + std::marker::Unpin> PaymentsEngine<T> {
-    pub fn new(input_source: T) -> Self {
+// Counts rather than contents, and no bound on the input source
+impl<T> fmt::Debug for PaymentsEngine<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PaymentsEngine")
+            .field("clients", &self.client_accounts.len())
+            .field("txs", &self.done_txs.lock().len())
+            .field("disputed", &self.disputed_txs.lock().len())
+            .finish()
+    }
+}
+
+/// Settings fixed when the engine is built, see `PaymentsEngineBuilder`.
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    pub channel_size: usize,
+    /// Most a client may hold, available and held funds together
+    pub max_balance: Option<Amount>,
+    pub min_deposit: Option<Amount>,
+    pub min_withdrawal: Option<Amount>,
+    /// Whether CSV input starts with a header row
+    pub has_header: bool,
+    /// What to do with input amounts given to more than four decimal places
+    pub amount_precision: AmountPrecision,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
         Self {
-            client_accounts: HashMap::default(),
-            input_source,
-            done_txs: Arc::new(Mutex::new(HashMap::default())),
-            disputed_txs: Arc::new(Mutex::new(HashSet::default())),
+            channel_size: CHANNEL_SIZE,
+            max_balance: None,
+            min_deposit: None,
+            min_withdrawal: None,
+            has_header: true,
+            amount_precision: AmountPrecision::default(),
         }
     }
+}
 
-    pub fn print_report(&self) {
-        Self::print_header();
-        for (id, account) in &self.client_accounts {
-            let account = account.lock();
-            println!(
-                "{},{},{},{},{}",
-                id,
-                account.available,
-                account.held,
-                account.available + account.held,
-                account.locked
-            );
+impl EngineConfig {
+    fn csv_options(&self) -> CsvOptions {
+        CsvOptions {
+            amount_precision: self.amount_precision,
+            has_header: self.has_header,
         }
     }
+}
 
-    pub async fn process_txs(&mut self) -> anyhow::Result<()> {
-        while let Some(tx) = self.input_source.next().await {
-            self.update(tx)?
-        }
-        Ok(())
+#[derive(Default)]
+pub struct PaymentsEngineBuilder {
+    config: EngineConfig,
+    audit_sink: Option<Box<dyn AuditSink>>,
+}
+
+impl PaymentsEngineBuilder {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn print_header() {
-        println!("client,available,held,total,locked");
+    /// Capacity of the channel between the file reader and the engine,
+    /// `CHANNEL_SIZE` by default. Panics if zero.
+    pub fn channel_size(mut self, channel_size: usize) -> Self {
+        assert!(channel_size > 0, "channel size must be positive");
+        self.config.channel_size = channel_size;
+        self
     }
 
-    fn update(&mut self, tx: Tx) -> anyhow::Result<()> {
-        if self.can_process_tx(&tx) {
-            self.update_client_accounts(&tx);
-            self.update_tx_history(tx)
-        } else {
-            Ok(())
-        }
+    /// Rejects deposits that would take a client's total above
+    /// `max_balance`.
+    pub fn max_balance(mut self, max_balance: Amount) -> Self {
+        self.config.max_balance = Some(max_balance);
+        self
     }
 
-    fn can_process_tx(&self, tx: &Tx) -> bool {
-        !self.client_account_frozen(tx) && self.sufficient_funds(tx)
+    /// Rejects deposits of less than `min_deposit`.
+    pub fn min_deposit(mut self, min_deposit: Amount) -> Self {
+        self.config.min_deposit = Some(min_deposit);
+        self
     }
 
-    fn client_account_frozen(&self, tx: &Tx) -> bool {
-        matches!(self.client_accounts.get(&tx.client_id),
-            Some(account) if account.lock().locked)
+    /// Rejects withdrawals of less than `min_withdrawal`.
+    pub fn min_withdrawal(mut self, min_withdrawal: Amount) -> Self {
+        self.config.min_withdrawal = Some(min_withdrawal);
+        self
     }
 
-    fn sufficient_funds(&self, tx: &Tx) -> bool {
-        if let TxInner::Withdrawal { amount } = tx.inner {
-            match self.client_accounts.get(&tx.client_id) {
-                Some(account) => account.lock().available >= amount,
-                None => false,
-            }
-        } else {
-            true
-        }
+    /// Set to false to read CSV input that has no header row; its columns
+    /// are then taken to be `type,client,tx,amount`. True by default.
+    pub fn has_header(mut self, has_header: bool) -> Self {
+        self.config.has_header = has_header;
+        self
     }
 
-    fn update_tx_history(&mut self, tx: Tx) -> anyhow::Result<()> {
-        if let std::collections::hash_map::Entry::Vacant(e) = self.done_txs.lock().entry(tx.tx_id) {
-            e.insert(tx);
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("tx_id {} already exists!"))
-        }
+    /// Whether input amounts with more than four decimal places are
+    /// rejected or rounded. Rounded by default.
+    pub fn amount_precision(mut self, amount_precision: AmountPrecision) -> Self {
+        self.config.amount_precision = amount_precision;
+        self
     }
 
-    fn dispute(&mut self, tx: &Tx) {
-        // We don't throw errors if something goes wrong
-        // Simply ignore the dispute
-        if let Some(disputed_tx) = self.done_txs.lock().get(&tx.tx_id) {
-            if let Some(amount) = match disputed_tx.inner {
-                TxInner::Withdrawal { amount } => Some(amount),
-                TxInner::Deposit { amount } => Some(amount),
-                _ => None,
-            } {
-                if let Some(client) = self.client_accounts.get_mut(&tx.client_id) {
-                    client.lock().dispute(amount);
-                    self.disputed_txs.lock().insert(tx.tx_id);
-                }
-            }
-        }
+    /// Reports whether each transaction was accepted or rejected to `sink`.
+    pub fn audit_sink(mut self, sink: Box<dyn AuditSink>) -> Self {
+        self.audit_sink = Some(sink);
+        self
     }
 
-    fn deposit(&mut self, client_id: ClientId, amount: Amount) {
-        self.client_accounts
-            .entry(client_id)
-            .or_insert_with(|| Arc::new(Mutex::new(ClientAccount::new())))
-            .lock()
-            .deposit(amount)
+    pub fn build<T: StreamExt<Item = Tx> + std::marker::Unpin>(
+        self,
+        input_source: T,
+    ) -> PaymentsEngine<T> {
+        let mut engine = PaymentsEngine::new(input_source);
+        engine.config = self.config;
+        engine.audit_sink = self.audit_sink;
+        engine
     }
 
-    fn withdrawal(&mut self, client_id: ClientId, amount: Amount) {
-        self.client_accounts
-            .entry(client_id)
-            .or_insert_with(|| Arc::new(Mutex::new(ClientAccount::new())))
-            .lock()
-            .withdrawal(amount)
+    /// Returns an engine fed by a background task reading the CSV file at
+    /// `path`. Must be called from within a Tokio runtime.
+    pub async fn from_csv_path(
+        self,
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<PaymentsEngine<ReceiverStream<Tx>>> {
+        // Open the file here so that a bad path is reported to the caller
+        // rather than from inside the reader task
+        let path = path.as_ref().to_path_buf();
+        let file = tokio::task::spawn_blocking(move || File::open(path)).await??;
+        let (sender, receiver) = channel(self.config.channel_size);
+        let options = self.config.csv_options();
+        tokio::spawn(async move {
+            if let Err(e) = fetch_csv_data_with_options(file, sender, options).await {
+                eprintln!("Error fetching csv data {:#}", e)
+            }
+        });
+        Ok(self.build(ReceiverStream::new(receiver)))
     }
+}
 
-    fn resolve(&mut self, tx: &Tx) {
-        if let Some(disputed_tx) = self.done_txs.lock().get(&tx.tx_id) {
-            if let Some(amount) = match disputed_tx.inner {
-                TxInner::Withdrawal { amount } => Some(amount),
-                TxInner::Deposit { amount } => Some(amount),
-                _ => None,
-            } {
-                if let Some(client) = self.client_accounts.get_mut(&tx.client_id) {
-                    client.lock().resolve(amount);
-                    self.disputed_txs.lock().remove(&tx.tx_id);
-                }
+impl PaymentsEngine<ReceiverStream<Tx>> {
+    /// Returns an engine fed by a background task reading the CSV file at
+    /// `path`. Must be called from within a Tokio runtime.
+    pub async fn from_csv_path(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        PaymentsEngineBuilder::new().from_csv_path(path).await
+    }
+
+    /// Stops taking new transactions from the channel, applies the ones
+    /// already queued and prints the report. If the queue is not drained
+    /// within `timeout` the report covers what was applied so far and
+    /// `EngineError::ShutdownTimeout` is returned.
+    pub async fn graceful_shutdown(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> Result<ProcessingSummary, EngineError> {
+        self.input_source.close();
+        let drained = tokio::time::timeout(timeout, self.process_txs()).await;
+        self.print_report();
+        let summary = self.processing_summary();
+        match drained {
+            Ok(Ok(())) => Ok(summary),
+            Ok(Err(e)) => {
+                eprintln!("Error processing txs: {}", e);
+                Ok(summary)
             }
+            Err(_) => Err(EngineError::ShutdownTimeout(summary)),
         }
     }
+}
 
-    fn chargeback(&mut self, tx: &Tx) {
-        if let Some(disputed_tx) = self.done_txs.lock().get(&tx.tx_id) {
-            if let Some(amount) = match disputed_tx.inner {
-                TxInner::Withdrawal { amount } => Some(amount),
-                TxInner::Deposit { amount } => Some(amount),
-                _ => None,
-            } {
-                if let Some(client) = self.client_accounts.get_mut(&tx.client_id) {
-                    client.lock().chargeback(amount);
-                }
-            }
+impl<T: StreamExt<Item = Tx> + std::marker::Unpin> PaymentsEngine<T> {
+    pub fn new(input_source: T) -> Self {
+        Self {
+            config: EngineConfig::default(),
+            client_accounts: HashMap::default(),
+            running_total_available: dec!(0.0),
+            running_total_held: dec!(0.0),
+            input_source,
+            done_txs: Arc::new(Mutex::new(HashMap::default())),
+            disputed_txs: Arc::new(Mutex::new(HashSet::default())),
+            charged_back_txs: Arc::new(Mutex::new(HashSet::default())),
+            exchange_rates: None,
+            audit_log: Vec::new(),
+            client_activity: HashMap::default(),
+            fraud_scorer: None,
+            ignored_txs: HashMap::default(),
+            accepted_txs: 0,
+            accepted_by_type: HashMap::default(),
+            panic_count: 0,
+            started: Some(std::time::Instant::now()),
+            clock: None,
+            velocity_limit: None,
+            velocity_windows: HashMap::default(),
+            daily_volume: None,
+            alert_handlers: Vec::new(),
+            alert_channel: None,
+            fee_account: None,
+            internal_tx_count: 0,
+            report_format: ReportFormat::default(),
+            audit_sink: None,
+            #[cfg(feature = "cache")]
+            balance_cache: None,
+            #[cfg(feature = "bench")]
+            update_durations: UpdateHistogram::default(),
+            #[cfg(feature = "deadlock-detection")]
+            deadlock_detector: false,
         }
     }
 
-    fn update_client_accounts(&mut self, tx: &Tx) {
-        match tx.inner {
-            TxInner::Deposit { amount } => self.deposit(tx.client_id, amount),
+    /// Like `new`, with room for `clients` accounts and `transactions`
+    /// deposits and withdrawals before any rehashing.
+    pub fn with_capacity(input_source: T, clients: usize, transactions: usize) -> Self {
+        let mut engine = Self::new(input_source);
+        engine.client_accounts.reserve(clients);
+        engine.done_txs.lock().reserve(transactions);
+        engine
+    }
+
+    pub fn with_report_format(mut self, format: ReportFormat) -> Self {
+        self.report_format = format;
+        self
+    }
+
+    /// Replaces the system clock, e.g. with a `MockClock` in tests.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn with_exchange_rate_provider(mut self, provider: Arc<dyn ExchangeRateProvider>) -> Self {
+        self.exchange_rates = Some(provider);
+        self
+    }
+
+    /// Ignores any transaction that `scorer` rates above `threshold`.
+    pub fn with_fraud_score(mut self, scorer: Arc<dyn FraudScorer>, threshold: FraudScore) -> Self {
+        self.fraud_scorer = Some((scorer, threshold));
+        self
+    }
+
+    /// Ignores any deposit, withdrawal or outgoing transfer that would take
+    /// the amount a client has moved over the past hour above
+    /// `max_amount_per_hour`. Fx transfers count in the sender's currency.
+    /// Fees and incoming transfers don't count.
+    pub fn with_velocity_check(mut self, max_amount_per_hour: Amount) -> Self {
+        self.velocity_limit = Some(max_amount_per_hour);
+        self
+    }
+
+    /// Once deposits plus withdrawals across all clients reach
+    /// `max_daily_volume`, or one would take them past it, raises
+    /// `Alert::DailyVolumeCapReached` and ignores every transaction until
+    /// midnight UTC.
+    pub fn with_daily_volume_limit(mut self, max_daily_volume: Amount) -> Self {
+        self.daily_volume = Some(DailyVolumeLimit {
+            max_daily_volume,
+            day: None,
+            volume: dec!(0.0),
+            halted: false,
+        });
+        self
+    }
+
+    /// Credits every fee to `fee_account` instead of discarding it. Fees
+    /// that would take the ledger over `max_balance` are rejected. Panics
+    /// if `fee_account` is the reserved client id.
+    pub fn with_transaction_fee_ledger(mut self, fee_account: ClientId) -> Self {
+        assert!(
+            fee_account != RESERVED_CLIENT_ID,
+            "fee ledger can't use the reserved client id"
+        );
+        self.fee_account = Some(fee_account);
+        self
+    }
+
+    pub fn on_alert(mut self, handler: impl Fn(&Alert) + Send + Sync + 'static) -> Self {
+        self.alert_handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Publishes every alert on `sender` so that subscribers receive them
+    /// without holding up the processing loop.
+    pub fn with_alert_channel(mut self, sender: broadcast::Sender<Alert>) -> Self {
+        self.alert_channel = Some(sender);
+        self
+    }
+
+    /// Checks for deadlocked threads every five seconds while `process_txs`
+    /// runs. Deadlocks are printed to stderr and published on the alert
+    /// channel; alert handlers are not called, as the check runs on a
+    /// thread of its own.
+    #[cfg(feature = "deadlock-detection")]
+    pub fn with_deadlock_detector(mut self) -> Self {
+        self.deadlock_detector = true;
+        self
+    }
+
+    /// Serves `account_snapshot` from a cache whose entries expire after
+    /// `ttl` and are dropped whenever the account changes.
+    #[cfg(feature = "cache")]
+    pub fn with_realtime_balance_cache(mut self, ttl: std::time::Duration) -> Self {
+        self.balance_cache = Some(moka::sync::Cache::builder().time_to_live(ttl).build());
+        self
+    }
+
+    pub async fn account_snapshot(&self, client_id: ClientId) -> Option<ClientAccountSnapshot> {
+        #[cfg(feature = "cache")]
+        if let Some(cache) = &self.balance_cache {
+            if let Some(snapshot) = cache.get(&client_id) {
+                return Some(snapshot);
+            }
+            let snapshot = self.read_account_snapshot(client_id)?;
+            cache.insert(client_id, snapshot.clone());
+            return Some(snapshot);
+        }
+        self.read_account_snapshot(client_id)
+    }
+
+    pub fn account_context(&self, client_id: ClientId) -> AccountContext {
+        let now = self.now();
+        let default_activity = ClientActivity::default();
+        let activity = self
+            .client_activity
+            .get(&client_id)
+            .unwrap_or(&default_activity);
+        AccountContext {
+            client_id,
+            account_age: activity
+                .first_activity
+                .map_or_else(Duration::zero, |first| now - first),
+            tx_count: activity.tx_count,
+            recent_dispute_count: activity.recent_dispute_count(now),
+            avg_deposit: average(activity.deposited, activity.deposit_count),
+            avg_withdrawal: average(activity.withdrawn, activity.withdrawal_count),
+            last_activity: activity.last_activity,
+        }
+    }
+
+    pub fn ignored_counts(&self) -> &HashMap<IgnoreReason, u64> {
+        &self.ignored_txs
+    }
+
+    pub fn fx_convert(
+        &self,
+        amount: Amount,
+        from: Currency,
+        to: Currency,
+    ) -> anyhow::Result<Amount> {
+        if from == to {
+            return Ok(amount);
+        }
+        let provider = self
+            .exchange_rates
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no exchange rate provider configured"))?;
+        let rate = provider.rate(from, to)?;
+        Ok((amount * rate).round_dp(4))
+    }
+
+    pub fn total_balance_in_currency(&self, currency: Currency) -> Amount {
+        self.accounts()
+            .filter(|(_, account)| account.currency == currency)
+            .map(|(_, account)| account.available)
+            .sum()
+    }
+
+    pub fn total_balance_converted_to(&self, currency: Currency) -> anyhow::Result<Amount> {
+        let mut total = dec!(0.0);
+        for (_, account) in self.accounts() {
+            total += self.fx_convert(account.available, account.currency, currency)?;
+        }
+        Ok(total)
+    }
+
+    /// Returns `(total available, total held)` for every currency in use.
+    pub fn balance_report_by_currency(&self) -> HashMap<Currency, (Amount, Amount)> {
+        let mut report = HashMap::new();
+        for (_, account) in self.accounts() {
+            let (available, held) = report
+                .entry(account.currency)
+                .or_insert((dec!(0.0), dec!(0.0)));
+            *available += account.available;
+            *held += account.held;
+        }
+        report
+    }
+
+    /// Converts every account into `base`, along with the recorded amounts
+    /// of its deposits and withdrawals so that later disputes stay
+    /// consistent. Fails without touching any account if a rate is missing.
+    pub fn convert_all_to_base_currency(
+        &mut self,
+        base: Currency,
+        provider: &dyn ExchangeRateProvider,
+    ) -> anyhow::Result<()> {
+        let mut client_rates = HashMap::new();
+        for id in self.sorted_client_ids() {
+            let currency = self.client_accounts[&id].lock().currency;
+            if currency != base {
+                client_rates.insert(id, provider.rate(currency, base)?);
+            }
+        }
+
+        let mut done_txs = self.done_txs.lock();
+        let disputed_txs = self.disputed_txs.lock();
+        let mut converted_held = HashMap::new();
+        for tx in done_txs.values_mut() {
+            let rate = match client_rates.get(&tx.client_id) {
+                Some(&rate) => rate,
+                None => continue,
+            };
+            if let TxInner::Deposit { amount } | TxInner::Withdrawal { amount } = &mut tx.inner {
+                *amount = (*amount * rate).round_dp(4);
+                if disputed_txs.contains(&tx.tx_id) {
+                    *converted_held.entry(tx.client_id).or_insert(dec!(0.0)) += *amount;
+                }
+            }
+        }
+        drop(disputed_txs);
+        drop(done_txs);
+
+        for id in self.sorted_client_ids() {
+            let rate = match client_rates.get(&id) {
+                Some(&rate) => rate,
+                None => continue,
+            };
+            let mut account = self.client_accounts[&id].lock();
+            let available = (account.available * rate).round_dp(4);
+            // Rebuilt from the converted disputes so that resolving them
+            // releases exactly what is held
+            let held = converted_held.get(&id).copied().unwrap_or(dec!(0.0));
+            self.audit_log.push(AuditEvent::CurrencyConversion {
+                client_id: id,
+                from_currency: account.currency,
+                to_currency: base,
+                rate,
+                available_before: account.available,
+                held_before: account.held,
+                available_after: available,
+                held_after: held,
+            });
+            account.available = available;
+            account.held = held;
+            account.total_deposited = (account.total_deposited * rate).round_dp(4);
+            account.total_withdrawn = (account.total_withdrawn * rate).round_dp(4);
+            account.currency = base;
+        }
+        self.recompute_running_totals();
+        #[cfg(feature = "cache")]
+        self.invalidate_all_cached_balances();
+        Ok(())
+    }
+
+    /// Withdraws `fee` from every unlocked account that can afford it.
+    /// Accounts that are locked or short of funds are skipped rather than
+    /// overdrawn. Each charge is recorded as an accepted fee, which cannot
+    /// be disputed. Returns how many were charged and skipped.
+    pub fn monthly_fee_pass(
+        &mut self,
+        fee: Amount,
+        effective_date: DateTime<Utc>,
+    ) -> FeePassSummary {
+        let mut summary = FeePassSummary::default();
+        if fee <= dec!(0) {
+            return summary;
+        }
+        for client_id in self.sorted_client_ids() {
+            let qualifies = {
+                let account = self.client_accounts[&client_id].lock();
+                !account.locked && account.available >= fee
+            };
+            if !qualifies {
+                summary.skipped += 1;
+                continue;
+            }
+            let tx = Tx {
+                client_id,
+                tx_id: self.next_internal_tx_id(),
+                inner: TxInner::Fee { amount: fee },
+            };
+            self.accepted_txs += 1;
+            *self
+                .accepted_by_type
+                .entry(tx.inner.type_name())
+                .or_insert(0) += 1;
+            self.audit(&tx, AuditOutcome::Accepted);
+            self.withdrawal(client_id, fee);
+            self.client_activity
+                .entry(client_id)
+                .or_default()
+                .record(&tx, effective_date);
+            self.done_txs.lock().insert(tx.tx_id, tx);
+            summary.charged += 1;
+        }
+        #[cfg(feature = "cache")]
+        self.invalidate_all_cached_balances();
+        summary
+    }
+
+    /// Sets up balances from `txs` without recording them, e.g. to restore
+    /// accounts from an earlier report. Only deposits and withdrawals with
+    /// a positive amount are accepted, and their tx ids stay free for real
+    /// transactions. Stops at the first transaction that cannot be applied.
+    pub fn warm_up(&mut self, txs: impl Iterator<Item = Tx>) -> anyhow::Result<()> {
+        for tx in txs {
+            if tx.client_id == RESERVED_CLIENT_ID {
+                return Err(anyhow::anyhow!(
+                    "warm-up transaction {} is for the reserved client id {}",
+                    tx.tx_id,
+                    RESERVED_CLIENT_ID
+                ));
+            }
+            match tx.inner {
+                TxInner::Deposit { amount } if amount > dec!(0) => {
+                    if self.balance_would_overflow(&tx) {
+                        return Err(anyhow::anyhow!(
+                            "warm-up deposit {} would overflow client {}'s balance",
+                            tx.tx_id,
+                            tx.client_id
+                        ));
+                    }
+                    self.deposit(tx.client_id, amount);
+                }
+                TxInner::Withdrawal { amount } if amount > dec!(0) => {
+                    if !self.sufficient_funds(&tx) {
+                        return Err(anyhow::anyhow!(
+                            "warm-up withdrawal {} exceeds client {}'s available funds",
+                            tx.tx_id,
+                            tx.client_id
+                        ));
+                    }
+                    if self.balance_would_overflow(&tx) {
+                        return Err(anyhow::anyhow!(
+                            "warm-up withdrawal {} would overflow client {}'s withdrawn total",
+                            tx.tx_id,
+                            tx.client_id
+                        ));
+                    }
+                    self.withdrawal(tx.client_id, amount);
+                }
+                _ => {
+                    return Err(anyhow::anyhow!(
+                    "warm-up transaction {} is not a deposit or withdrawal of a positive amount",
+                    tx.tx_id
+                ))
+                }
+            }
+        }
+        #[cfg(feature = "cache")]
+        self.invalidate_all_cached_balances();
+        Ok(())
+    }
+
+    /// Forgets every account and transaction, as if the engine had just
+    /// been built. Its configuration, limits, handlers and input source
+    /// are kept.
+    pub fn reset(&mut self) {
+        self.client_accounts.clear();
+        self.running_total_available = dec!(0.0);
+        self.running_total_held = dec!(0.0);
+        self.done_txs.lock().clear();
+        self.disputed_txs.lock().clear();
+        self.charged_back_txs.lock().clear();
+        self.audit_log.clear();
+        self.client_activity.clear();
+        self.ignored_txs.clear();
+        self.accepted_txs = 0;
+        self.accepted_by_type.clear();
+        self.panic_count = 0;
+        self.velocity_windows.clear();
+        if let Some(limit) = &mut self.daily_volume {
+            limit.day = None;
+            limit.volume = dec!(0.0);
+            limit.halted = false;
+        }
+        self.internal_tx_count = 0;
+        #[cfg(feature = "bench")]
+        {
+            self.update_durations = UpdateHistogram::default();
+        }
+        #[cfg(feature = "cache")]
+        self.invalidate_all_cached_balances();
+    }
+
+    /// Closes `client_id`'s account and forgets its transactions, so their
+    /// tx ids may be used again. Returns whether the client had an account.
+    pub fn reset_account(&mut self, client_id: ClientId) -> bool {
+        let account = match self.client_accounts.remove(&client_id) {
+            Some(account) => account,
+            None => return false,
+        };
+        let account = account.lock();
+        self.running_total_available -= account.available;
+        self.running_total_held -= account.held;
+        drop(account);
+        let mut done_txs = self.done_txs.lock();
+        let mut disputed_txs = self.disputed_txs.lock();
+        let mut charged_back_txs = self.charged_back_txs.lock();
+        done_txs.retain(|tx_id, tx| {
+            let keep = tx.client_id != client_id;
+            if !keep {
+                disputed_txs.remove(tx_id);
+                charged_back_txs.remove(tx_id);
+            }
+            keep
+        });
+        drop(charged_back_txs);
+        drop(disputed_txs);
+        drop(done_txs);
+        self.client_activity.remove(&client_id);
+        self.velocity_windows.remove(&client_id);
+        #[cfg(feature = "cache")]
+        self.invalidate_all_cached_balances();
+        true
+    }
+
+    /// Takes over `other`'s clients and transactions. Fails without changing
+    /// this engine if the two share a client or a tx id, so disjoint engines
+    /// give the same report whichever order they are merged in.
+    pub fn merge<U>(&mut self, other: PaymentsEngine<U>) -> anyhow::Result<()> {
+        if let Some(client_id) = other
+            .client_accounts
+            .keys()
+            .find(|client_id| self.client_accounts.contains_key(client_id))
+        {
+            return Err(anyhow::anyhow!("client {} is in both engines", client_id));
+        }
+        // The shards of process_txs_parallel already share one history
+        if !Arc::ptr_eq(&self.done_txs, &other.done_txs) {
+            let mut done_txs = self.done_txs.lock();
+            let mut other_done_txs = other.done_txs.lock();
+            if let Some(tx_id) = other_done_txs
+                .keys()
+                .find(|tx_id| done_txs.contains_key(tx_id))
+            {
+                return Err(anyhow::anyhow!("tx {} is in both engines", tx_id));
+            }
+            done_txs.extend(other_done_txs.drain());
+            drop(other_done_txs);
+            drop(done_txs);
+            let other_disputed_txs = std::mem::take(&mut *other.disputed_txs.lock());
+            self.disputed_txs.lock().extend(other_disputed_txs);
+            let other_charged_back_txs = std::mem::take(&mut *other.charged_back_txs.lock());
+            self.charged_back_txs.lock().extend(other_charged_back_txs);
+        }
+        self.client_accounts.extend(other.client_accounts);
+        self.client_activity.extend(other.client_activity);
+        self.velocity_windows.extend(other.velocity_windows);
+        for (reason, count) in other.ignored_txs {
+            *self.ignored_txs.entry(reason).or_insert(0) += count;
+        }
+        self.accepted_txs += other.accepted_txs;
+        for (type_name, count) in other.accepted_by_type {
+            *self.accepted_by_type.entry(type_name).or_insert(0) += count;
+        }
+        self.panic_count += other.panic_count;
+        self.audit_log.extend(other.audit_log);
+        self.internal_tx_count = self.internal_tx_count.max(other.internal_tx_count);
+        #[cfg(feature = "bench")]
+        self.update_durations.merge(&other.update_durations);
+        self.recompute_running_totals();
+        #[cfg(feature = "cache")]
+        self.invalidate_all_cached_balances();
+        Ok(())
+    }
+
+    /// Undoes a deposit or withdrawal that is not under dispute and forgets
+    /// it, so that its tx id may be used again. Statistics, activity and
+    /// velocity are left as though it had never been accepted. Disputes,
+    /// resolves and chargebacks are never recorded and so cannot be rolled
+    /// back, and neither can charged back txs or those of locked accounts.
+    pub fn rollback_tx(&mut self, tx_id: TxId) -> Result<(), EngineError> {
+        if self.disputed_txs.lock().contains(&tx_id) {
+            return Err(EngineError::TxInDispute(tx_id));
+        }
+        if self.charged_back_txs.lock().contains(&tx_id) {
+            return Err(EngineError::TxChargedBack(tx_id));
+        }
+        let mut done_txs = self.done_txs.lock();
+        let tx = done_txs.get(&tx_id).ok_or(EngineError::TxNotFound(tx_id))?;
+        let mut account = self
+            .client_accounts
+            .get(&tx.client_id)
+            .ok_or(EngineError::TxNotFound(tx_id))?
+            .lock();
+        if account.locked {
+            return Err(EngineError::AccountLocked(tx.client_id));
+        }
+        match tx.inner {
+            TxInner::Deposit { amount } => {
+                if account.available < amount {
+                    return Err(EngineError::InsufficientFunds(tx_id));
+                }
+                account.undo_deposit(amount);
+                self.running_total_available -= amount;
+            }
+            TxInner::Withdrawal { amount } => {
+                account.undo_withdrawal(amount);
+                self.running_total_available += amount;
+            }
+            _ => return Err(EngineError::TxNotReversible(tx_id)),
+        }
+        drop(account);
+        let tx = done_txs.remove(&tx_id).expect("rolled back tx is recorded");
+        drop(done_txs);
+        self.accepted_txs -= 1;
+        if let Some(count) = self.accepted_by_type.get_mut(tx.inner.type_name()) {
+            *count -= 1;
+        }
+        if let Some(activity) = self.client_activity.get_mut(&tx.client_id) {
+            activity.undo(&tx);
+        }
+        if let Some(window) = self.velocity_windows.get_mut(&tx.client_id) {
+            window.retain(|&(_, windowed_tx_id, _)| windowed_tx_id != tx_id);
+        }
+        #[cfg(feature = "cache")]
+        self.invalidate_all_cached_balances();
+        Ok(())
+    }
+
+    /// Credits `rate_bps` basis points of interest on every unlocked
+    /// account's available funds, compounding over `periods`. Returns the
+    /// total interest credited to each account that earned any.
+    pub fn apply_interest_compound(
+        &mut self,
+        rate_bps: u32,
+        periods: u32,
+        effective_date: DateTime<Utc>,
+    ) -> HashMap<ClientId, Amount> {
+        let rate = Amount::from(rate_bps) / dec!(10000);
+        let mut credited = HashMap::new();
+        for client_id in self.sorted_client_ids() {
+            let interest = {
+                let mut account = self.client_accounts[&client_id].lock();
+                if account.locked {
+                    continue;
+                }
+                let mut interest = dec!(0.0);
+                for _ in 0..periods {
+                    let period_interest = match account.available.checked_mul(rate) {
+                        Some(period_interest) => period_interest.round_dp(4),
+                        None => break,
+                    };
+                    if !account.can_credit(period_interest)
+                        || !self.engine_total_can_grow(interest + period_interest)
+                    {
+                        break;
+                    }
+                    account.deposit(period_interest);
+                    interest += period_interest;
+                }
+                interest
+            };
+            if interest <= dec!(0) {
+                continue;
+            }
+            self.running_total_available += interest;
+            let tx = Tx {
+                client_id,
+                tx_id: self.next_internal_tx_id(),
+                inner: TxInner::Deposit { amount: interest },
+            };
+            self.client_activity
+                .entry(client_id)
+                .or_default()
+                .record(&tx, effective_date);
+            self.done_txs.lock().insert(tx.tx_id, tx);
+            credited.insert(client_id, interest);
+        }
+        #[cfg(feature = "cache")]
+        self.invalidate_all_cached_balances();
+        credited
+    }
+
+    pub fn simulate_chargeback_impact(&self, client_id: ClientId, tx_id: TxId) -> SimulationResult {
+        let (available, held, locked) = match self.client_accounts.get(&client_id) {
+            Some(account) => {
+                let account = account.lock();
+                (account.available, account.held, account.locked)
+            }
+            None => {
+                return SimulationResult {
+                    available: dec!(0.0),
+                    held: dec!(0.0),
+                    locked: false,
+                    rejection: Some(ChargebackRejection::AccountNotFound),
+                }
+            }
+        };
+        let amount = if locked {
+            Err(ChargebackRejection::AccountLocked)
+        } else {
+            self.chargeback_amount(client_id, tx_id)
+        };
+        match amount {
+            Ok(amount) => SimulationResult {
+                available,
+                held: held - amount,
+                locked: true,
+                rejection: None,
+            },
+            Err(rejection) => SimulationResult {
+                available,
+                held,
+                locked,
+                rejection: Some(rejection),
+            },
+        }
+    }
+
+    /// Prints the report in the format chosen with `with_report_format`.
+    /// Multi-currency ledgers get an extra currency column in CSV.
+    pub fn print_report(&self) {
+        let result = match self.report_format {
+            ReportFormat::Csv if self.is_multicurrency() => {
+                self.write_multicurrency_report(std::io::stdout())
+            }
+            format => self.write_report_with(report_writer(format, std::io::stdout()).as_mut()),
+        };
+        if let Err(e) = result {
+            eprintln!("Error writing report: {}", e)
+        }
+    }
+
+    pub fn is_multicurrency(&self) -> bool {
+        let mut currencies = self
+            .client_accounts
+            .values()
+            .map(|account| account.lock().currency);
+        match currencies.next() {
+            Some(first) => currencies.any(|currency| currency != first),
+            None => false,
+        }
+    }
+
+    /// Every client's account, in ascending client id order. Each account
+    /// is copied as it is reached, so the engine is never left locked.
+    pub fn accounts(&self) -> impl Iterator<Item = (ClientId, ClientAccount)> + '_ {
+        self.sorted_client_ids()
+            .into_iter()
+            .map(move |id| (id, self.client_accounts[&id].lock().clone()))
+    }
+
+    /// One row per client, in ascending client id order.
+    pub fn report_rows(&self) -> Vec<ReportRow> {
+        self.accounts()
+            .map(|(id, account)| report_row(id, &account))
+            .collect()
+    }
+
+    /// Like `report_rows`, but builds each row only when the stream is
+    /// polled for it.
+    pub fn stream_report(&self) -> impl Stream<Item = ReportRow> + '_ {
+        tokio_stream::iter(self.accounts()).map(|(id, account)| report_row(id, &account))
+    }
+
+    pub fn write_report<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        self.write_report_with(&mut CsvReportWriter::new(writer))
+    }
+
+    /// The CSV report as a string.
+    pub fn accounts_as_csv(&self) -> String {
+        let mut out = Vec::new();
+        self.write_report(&mut out)
+            .expect("writing a report to memory cannot fail");
+        String::from_utf8(out).expect("report is not UTF-8")
+    }
+
+    pub fn write_report_with(&self, writer: &mut dyn ReportWriter) -> anyhow::Result<()> {
+        writer.write_header()?;
+        for row in self.report_rows() {
+            writer.write_row(&row)?;
+        }
+        writer.finish()
+    }
+
+    pub fn write_multicurrency_report<W: Write>(&self, mut writer: W) -> anyhow::Result<()> {
+        writeln!(writer, "client,currency,available,held,total,locked")?;
+        for (id, account) in self.accounts() {
+            let row = report_row(id, &account);
+            writeln!(
+                writer,
+                "{},{},{},{},{},{}",
+                row.client_id, account.currency, row.available, row.held, row.total, row.locked
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Unknown clients are reported as unlocked.
+    pub fn is_account_locked(&self, client_id: ClientId) -> bool {
+        self.client_accounts
+            .get(&client_id)
+            .is_some_and(|account| account.lock().locked)
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.client_accounts.len()
+    }
+
+    /// Number of transactions applied, not counting disputes, resolves and
+    /// chargebacks but including those the engine generated itself.
+    pub fn transaction_count(&self) -> usize {
+        self.done_txs.lock().len()
+    }
+
+    /// Warns when the transaction history exceeds a million entries, more
+    /// than 5% of accounts are locked or more than 1% of transactions were
+    /// ignored.
+    pub fn health_check(&self) -> HealthStatus {
+        let warn_if = |name, exceeded| HealthCheck {
+            name,
+            status: if exceeded {
+                CheckStatus::Warn
+            } else {
+                CheckStatus::Ok
+            },
+        };
+        let locked_accounts = self
+            .client_accounts
+            .values()
+            .filter(|account| account.lock().locked)
+            .count();
+        let ignored_txs: u64 = self.ignored_txs.values().sum();
+        HealthStatus::from_checks(vec![
+            warn_if(
+                "tx_history_size",
+                self.done_txs.lock().len() > TX_HISTORY_WARN_SIZE,
+            ),
+            warn_if(
+                "locked_account_ratio",
+                ratio(locked_accounts as u64, self.client_accounts.len() as u64)
+                    > LOCKED_ACCOUNT_WARN_RATIO,
+            ),
+            warn_if(
+                "error_rate",
+                ratio(ignored_txs, ignored_txs + self.accepted_txs) > ERROR_RATE_WARN_RATIO,
+            ),
+        ])
+    }
+
+    pub fn save_state(&self) -> EngineState {
+        let client_accounts = self
+            .accounts()
+            .map(|(id, account)| {
+                let data = ClientAccountData {
+                    available: account.available,
+                    held: account.held,
+                    locked: account.locked,
+                    currency: account.currency,
+                    total_deposited: account.total_deposited,
+                    total_withdrawn: account.total_withdrawn,
+                };
+                (id, data)
+            })
+            .collect();
+        let mut disputed_txs: Vec<_> = self.disputed_txs.lock().iter().copied().collect();
+        disputed_txs.sort_unstable();
+        let mut charged_back_txs: Vec<_> = self.charged_back_txs.lock().iter().copied().collect();
+        charged_back_txs.sort_unstable();
+        EngineState {
+            client_accounts,
+            done_txs: self.done_txs.lock().clone(),
+            disputed_txs,
+            charged_back_txs,
+        }
+    }
+
+    /// Replaces the accounts and transaction history with those in `state`,
+    /// so that later transactions may dispute the ones it holds.
+    pub fn load_state(&mut self, state: EngineState) {
+        self.client_accounts = state
+            .client_accounts
+            .into_iter()
+            .map(|(id, data)| {
+                let account = ClientAccount {
+                    available: data.available,
+                    held: data.held,
+                    locked: data.locked,
+                    currency: data.currency,
+                    total_deposited: data.total_deposited,
+                    total_withdrawn: data.total_withdrawn,
+                };
+                (id, Arc::new(Mutex::new(account)))
+            })
+            .collect();
+        *self.done_txs.lock() = state.done_txs;
+        *self.disputed_txs.lock() = state.disputed_txs.into_iter().collect();
+        *self.charged_back_txs.lock() = state.charged_back_txs.into_iter().collect();
+        self.recompute_running_totals();
+        #[cfg(feature = "cache")]
+        self.invalidate_all_cached_balances();
+    }
+
+    pub fn processing_summary(&self) -> ProcessingSummary {
+        ProcessingSummary {
+            transactions_accepted: self.accepted_txs,
+            transactions_ignored: self.ignored_txs.values().sum(),
+            clients: self.client_accounts.len(),
+            #[cfg(feature = "bench")]
+            update_timings: UpdateTimings::from_histogram(&self.update_durations),
+        }
+    }
+
+    /// Ready once at least one transaction has been accepted, and from then
+    /// on until `reset`. The engine has no circuit breaker yet, so nothing
+    /// else makes it unready.
+    pub fn readiness_check(&self) -> bool {
+        self.accepted_txs > 0
+    }
+
+    /// Available funds across every account, in constant time.
+    pub fn total_available(&self) -> Amount {
+        self.running_total_available
+    }
+
+    /// Held funds across every account, in constant time.
+    pub fn total_held(&self) -> Amount {
+        self.running_total_held
+    }
+
+    /// Available and held funds across every account, in constant time.
+    pub fn total_assets(&self) -> Amount {
+        self.running_total_available + self.running_total_held
+    }
+
+    /// Sum of the client's transactions currently under dispute. Should
+    /// always equal the account's held funds.
+    pub fn disputed_amount_for_client(&self, client_id: ClientId) -> Amount {
+        let done_txs = self.done_txs.lock();
+        let disputed_txs = self.disputed_txs.lock();
+        disputed_txs
+            .iter()
+            .filter_map(|tx_id| done_txs.get(tx_id))
+            .filter(|tx| tx.client_id == client_id)
+            .filter_map(|tx| match tx.inner {
+                TxInner::Deposit { amount } | TxInner::Withdrawal { amount } => Some(amount),
+                _ => None,
+            })
+            .fold(dec!(0.0), |sum, amount| sum + amount)
+    }
+
+    pub fn statistics(&self) -> EngineStatistics {
+        let accepted = |type_name| self.accepted_by_type.get(type_name).copied().unwrap_or(0);
+        let uptime = self
+            .started
+            .map_or_else(Default::default, |started| started.elapsed());
+        let processed = self.accepted_txs + self.ignored_txs.values().sum::<u64>();
+        let mut stats = EngineStatistics {
+            total_clients: self.client_accounts.len(),
+            total_transactions: self.done_txs.lock().len() as u64,
+            total_disputed: self.disputed_txs.lock().len(),
+            total_locked_accounts: 0,
+            total_available: self.running_total_available,
+            total_held: self.running_total_held,
+            deposit_count: accepted("deposit"),
+            withdrawal_count: accepted("withdrawal"),
+            dispute_count: accepted("dispute"),
+            resolve_count: accepted("resolve"),
+            chargeback_count: accepted("chargeback"),
+            ignored_by_reason: self.ignored_txs.clone(),
+            uptime,
+            txs_per_second: match uptime.as_secs_f64() {
+                secs if secs > 0.0 => processed as f64 / secs,
+                _ => 0.0,
+            },
+        };
+        for (_, account) in self.accounts() {
+            if account.locked {
+                stats.total_locked_accounts += 1;
+            }
+        }
+        stats
+    }
+
+    pub fn drain_audit_events(&mut self) -> Vec<AuditEvent> {
+        std::mem::take(&mut self.audit_log)
+    }
+
+    pub async fn process_txs(&mut self) -> anyhow::Result<()> {
+        // Stops the detector when dropped
+        #[cfg(feature = "deadlock-detection")]
+        let _detector = self
+            .deadlock_detector
+            .then(|| spawn_deadlock_detector(self.alert_channel.clone()));
+        while let Some(tx) = self.input_source.next().await {
+            self.update(tx)?
+        }
+        Ok(())
+    }
+
+    /// Like `process_txs`, also telling `callback` what became of each
+    /// transaction.
+    pub async fn process_txs_with_callback<F>(&mut self, callback: F) -> anyhow::Result<()>
+    where
+        F: Fn(&Tx, &TxOutcome),
+    {
+        while let Some(tx) = self.input_source.next().await {
+            let outcome = self.process_one_tx(tx.clone())?;
+            callback(&tx, &outcome);
+        }
+        Ok(())
+    }
+
+    /// Like `process_txs`, spread over `shards` tasks by client id modulo
+    /// `shards`. A transfer between shards holds up the input until both
+    /// sides are settled. The shards are merged back into this engine once
+    /// the input runs out.
+    ///
+    /// Tx ids must be unique across clients: two shards reusing one at the
+    /// same moment stop processing with an error rather than ignoring the
+    /// second. Fee ledgers, daily volume caps, alert handlers and audit
+    /// sinks need every transaction in input order and are not supported.
+    pub async fn process_txs_parallel(&mut self, shards: usize) -> anyhow::Result<()> {
+        if shards == 0 {
+            return Err(anyhow::anyhow!("need at least one shard"));
+        }
+        if self.fee_account.is_some()
+            || self.daily_volume.is_some()
+            || !self.alert_handlers.is_empty()
+            || self.audit_sink.is_some()
+        {
+            return Err(anyhow::anyhow!(
+                "fee ledgers, daily volume caps, alert handlers and audit sinks can't be sharded"
+            ));
+        }
+        let shard_of = |client_id: ClientId| usize::from(client_id) % shards;
+        let mut engines: Vec<_> = (0..shards).map(|_| self.empty_shard()).collect();
+        for (client_id, account) in self.client_accounts.drain() {
+            engines[shard_of(client_id)]
+                .client_accounts
+                .insert(client_id, account);
+        }
+        for (client_id, activity) in self.client_activity.drain() {
+            engines[shard_of(client_id)]
+                .client_activity
+                .insert(client_id, activity);
+        }
+        for (client_id, window) in self.velocity_windows.drain() {
+            engines[shard_of(client_id)]
+                .velocity_windows
+                .insert(client_id, window);
+        }
+
+        let mut senders = Vec::with_capacity(shards);
+        let mut tasks = Vec::with_capacity(shards);
+        for mut engine in engines {
+            let (sender, receiver) = channel(self.config.channel_size);
+            senders.push(sender);
+            tasks.push(tokio::spawn(async move {
+                engine.run_shard(receiver).await?;
+                Ok::<_, anyhow::Error>(engine)
+            }));
+        }
+        let dispatched = self.dispatch_to_shards(&senders, shard_of).await;
+        drop(senders);
+
+        // A shard's own error explains why sending to it failed
+        let mut result = Ok(());
+        for task in tasks {
+            match task.await? {
+                Ok(engine) => self.merge(engine)?,
+                Err(e) => result = result.and(Err(e)),
+            }
+        }
+        result.and(dispatched)
+    }
+
+    /// Like `process_txs`, but a transaction whose processing panics, say in
+    /// a fraud scorer, is logged and skipped. It may have been partly
+    /// applied. Once more than `max_panics` panics have been caught the next
+    /// one is let through.
+    pub async fn process_txs_with_panic_recovery(&mut self, max_panics: u64) -> anyhow::Result<()> {
+        while let Some(tx) = self.input_source.next().await {
+            let tx_id = tx.tx_id;
+            match std::panic::catch_unwind(AssertUnwindSafe(|| self.update(tx))) {
+                Ok(result) => result?,
+                Err(panic) => {
+                    self.panic_count += 1;
+                    if self.panic_count > max_panics {
+                        std::panic::resume_unwind(panic);
+                    }
+                    eprintln!(
+                        "skipped tx {} after a panic: {}",
+                        tx_id,
+                        panic_message(&*panic)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Panics caught by `process_txs_with_panic_recovery`.
+    pub fn panic_count(&self) -> u64 {
+        self.panic_count
+    }
+
+    /// Applies every transaction in the files at `paths`, one file after
+    /// another, each read in the format its extension suggests. The
+    /// engine's own input source is left alone.
+    pub async fn process_txs_from_file(
+        &mut self,
+        paths: &[impl AsRef<Path>],
+    ) -> anyhow::Result<()> {
+        for path in paths {
+            let (sender, receiver) = channel(self.config.channel_size);
+            let source = InputSource::detect(path.as_ref()).with_options(self.config.csv_options());
+            let reader = tokio::spawn(source.send_to(sender));
+            self.process_txs_from_task(receiver, reader).await?;
+        }
+        Ok(())
+    }
+
+    /// Like `process_txs_from_file`, for CSV read from stdin, an in-memory
+    /// buffer or any other reader.
+    pub async fn process_txs_from_reader(
+        &mut self,
+        reader: impl Read + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let (sender, receiver) = channel(self.config.channel_size);
+        let options = self.config.csv_options();
+        let reader = tokio::task::spawn_blocking(move || {
+            fetch_csv_data_blocking_with_options(reader, sender, options)
+        });
+        self.process_txs_from_task(receiver, reader).await
+    }
+
+    /// Applies `txs` in order without going through the input source, and
+    /// says for each one whether it was applied. Should the engine's own
+    /// records turn out to be inconsistent, that transaction's result is
+    /// `EngineError::TxAlreadyRecorded` and the rest are still applied.
+    pub fn process_txs_batch(&mut self, txs: impl IntoIterator<Item = Tx>) -> Vec<TxResult> {
+        txs.into_iter()
+            .map(|tx| self.apply_tx(tx).and_then(|result| result))
+            .collect()
+    }
+
+    /// Applies `tx` and says whether it was applied or why it was ignored.
+    /// Any error without an ignore reason is returned.
+    pub fn process_one_tx(&mut self, tx: Tx) -> Result<TxOutcome, EngineError> {
+        match self.apply_tx(tx)? {
+            Ok(()) => Ok(TxOutcome::Applied),
+            Err(e) => e.ignore_reason().map(TxOutcome::Ignored).ok_or(e),
+        }
+    }
+
+    /// Like `process_txs_from_reader`, for CSV held in a string. Returns
+    /// the summary of everything the engine has processed so far.
+    pub async fn process_txs_from_csv_str(
+        &mut self,
+        csv: &str,
+    ) -> anyhow::Result<ProcessingSummary> {
+        self.process_txs_from_reader(std::io::Cursor::new(csv.as_bytes().to_vec()))
+            .await?;
+        Ok(self.processing_summary())
+    }
+
+    /// Applies what `reader` sends on the other end of `receiver`, then
+    /// reports whether it read its whole input.
+    async fn process_txs_from_task(
+        &mut self,
+        receiver: Receiver<Tx>,
+        reader: JoinHandle<anyhow::Result<()>>,
+    ) -> anyhow::Result<()> {
+        let mut txs = ReceiverStream::new(receiver);
+        while let Some(tx) = txs.next().await {
+            self.update(tx)?
+        }
+        reader.await?
+    }
+
+    /// An engine with this one's settings and transaction history, but no
+    /// clients of its own yet.
+    fn empty_shard(&self) -> PaymentsEngine<Empty<Tx>> {
+        let mut shard = PaymentsEngine::new(tokio_stream::empty());
+        shard.config = self.config.clone();
+        shard.done_txs = Arc::clone(&self.done_txs);
+        shard.disputed_txs = Arc::clone(&self.disputed_txs);
+        shard.charged_back_txs = Arc::clone(&self.charged_back_txs);
+        shard.exchange_rates = self.exchange_rates.clone();
+        shard.fraud_scorer = self.fraud_scorer.clone();
+        shard.clock = self.clock.clone();
+        shard.velocity_limit = self.velocity_limit;
+        shard.alert_channel = self.alert_channel.clone();
+        shard
+    }
+
+    async fn dispatch_to_shards(
+        &mut self,
+        shards: &[Sender<ShardMsg>],
+        shard_of: impl Fn(ClientId) -> usize,
+    ) -> anyhow::Result<()> {
+        while let Some(tx) = self.input_source.next().await {
+            let shard = &shards[shard_of(tx.client_id)];
+            match recipient(&tx).filter(|&to| shard_of(to) != shard_of(tx.client_id)) {
+                None => send_to_shard(shard, ShardMsg::Tx(tx)).await?,
+                Some(to) => {
+                    // Nothing else reaches either shard until the recipient's
+                    // account is back home, keeping both clients' txs in order
+                    let home = &shards[shard_of(to)];
+                    move_account(to, home, shard).await?;
+                    send_to_shard(shard, ShardMsg::Tx(tx)).await?;
+                    move_account(to, shard, home).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_shard(&mut self, mut receiver: Receiver<ShardMsg>) -> anyhow::Result<()> {
+        while let Some(msg) = receiver.recv().await {
+            match msg {
+                ShardMsg::Tx(tx) => self.update(tx)?,
+                ShardMsg::Release(client_id, reply) => {
+                    // Only fails once the dispatcher has given up
+                    let _ = reply.send(self.client_accounts.remove(&client_id));
+                }
+                ShardMsg::Adopt(client_id, account) => {
+                    self.client_accounts.insert(client_id, account);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        self.clock
+            .as_ref()
+            .map_or_else(Utc::now, |clock| clock.now())
+    }
+
+    fn read_account_snapshot(&self, client_id: ClientId) -> Option<ClientAccountSnapshot> {
+        let account = self.client_accounts.get(&client_id)?.lock();
+        Some(ClientAccountSnapshot {
+            client_id,
+            available: account.available,
+            held: account.held,
+            total: account.total(),
+            locked: account.locked,
+            currency: account.currency,
+        })
+    }
+
+    /// Drops the cached snapshots of every account `tx` may have changed.
+    #[cfg(feature = "cache")]
+    fn invalidate_cached_balances(&self, tx: &Tx) {
+        let cache = match &self.balance_cache {
+            Some(cache) => cache,
+            None => return,
+        };
+        let mut client_ids = vec![tx.client_id];
+        match tx.inner {
+            TxInner::Transfer { to_client_id, .. } => client_ids.push(to_client_id),
+            TxInner::FxTransfer { to_client, .. } => client_ids.push(to_client),
+            TxInner::Fee { .. } => client_ids.extend(self.fee_account),
+            _ => {}
+        }
+        for client_id in client_ids {
+            cache.invalidate(&client_id);
+        }
+    }
+
+    #[cfg(feature = "cache")]
+    fn invalidate_all_cached_balances(&self) {
+        if let Some(cache) = &self.balance_cache {
+            cache.invalidate_all();
+        }
+    }
+
+    fn recompute_running_totals(&mut self) {
+        let (available, held) = self
+            .accounts()
+            .fold((dec!(0.0), dec!(0.0)), |(available, held), (_, account)| {
+                (available + account.available, held + account.held)
+            });
+        self.running_total_available = available;
+        self.running_total_held = held;
+    }
+
+    fn sorted_client_ids(&self) -> Vec<ClientId> {
+        let mut client_ids: Vec<_> = self.client_accounts.keys().copied().collect();
+        client_ids.sort_unstable();
+        client_ids
+    }
+
+    fn emit_alert(&self, alert: Alert) {
+        for handler in &self.alert_handlers {
+            handler(&alert);
+        }
+        if let Some(sender) = &self.alert_channel {
+            // Sending only fails when nobody is subscribed
+            let _ = sender.send(alert);
+        }
+    }
+
+    #[cfg(not(feature = "bench"))]
+    fn update(&mut self, tx: Tx) -> anyhow::Result<()> {
+        self.apply_tx(tx).map(|_| ()).map_err(Into::into)
+    }
+
+    #[cfg(feature = "bench")]
+    fn update(&mut self, tx: Tx) -> anyhow::Result<()> {
+        let start = std::time::Instant::now();
+        let result = self.apply_tx(tx).map(|_| ());
+        self.update_durations.record(start.elapsed());
+        result.map_err(Into::into)
+    }
+
+    // The outer error means the engine's own records are inconsistent and
+    // processing must stop; the inner one that `tx` was turned away
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, tx), fields(tx_id = tx.tx_id, client_id = tx.client_id))
+    )]
+    fn apply_tx(&mut self, tx: Tx) -> Result<TxResult, EngineError> {
+        let now = self.now();
+        if let Some(limit) = &mut self.daily_volume {
+            limit.roll_over(now.naive_utc().date());
+        }
+        // Checked first so that breaking a configured limit is told apart
+        // from the reasons any engine would ignore a transaction for
+        if let Err(e) = self.check_limits(&tx) {
+            if let Some(reason) = e.ignore_reason() {
+                *self.ignored_txs.entry(reason).or_insert(0) += 1;
+                self.audit(&tx, AuditOutcome::Rejected { reason });
+            }
+            return Ok(Err(e));
+        }
+        if let Err(reason) = self.can_process_tx(&tx) {
+            if reason == IgnoreReason::DailyVolumeCapReached {
+                self.halt_for_the_day();
+            }
+            *self.ignored_txs.entry(reason).or_insert(0) += 1;
+            self.audit(&tx, AuditOutcome::Rejected { reason });
+            return Ok(Err(EngineError::Ignored {
+                tx_id: tx.tx_id,
+                reason,
+            }));
+        }
+        self.accepted_txs += 1;
+        *self
+            .accepted_by_type
+            .entry(tx.inner.type_name())
+            .or_insert(0) += 1;
+        self.audit(&tx, AuditOutcome::Accepted);
+        self.update_client_accounts(&tx);
+        #[cfg(feature = "cache")]
+        self.invalidate_cached_balances(&tx);
+        self.record_daily_volume(&tx);
+        self.client_activity
+            .entry(tx.client_id)
+            .or_default()
+            .record(&tx, now);
+        self.record_velocity(&tx, now);
+        if !tx.inner.references_prior_tx() {
+            self.update_tx_history(tx)?
+        }
+        Ok(Ok(()))
+    }
+
+    fn check_limits(&self, tx: &Tx) -> Result<(), EngineError> {
+        match (
+            &tx.inner,
+            self.config.min_deposit,
+            self.config.min_withdrawal,
+        ) {
+            (TxInner::Deposit { amount }, Some(min_deposit), _) if *amount < min_deposit => {
+                return Err(EngineError::BelowMinimumDeposit {
+                    tx_id: tx.tx_id,
+                    min_deposit,
+                })
+            }
+            (TxInner::Withdrawal { amount }, _, Some(min_withdrawal))
+                if *amount < min_withdrawal =>
+            {
+                return Err(EngineError::BelowMinimumWithdrawal {
+                    tx_id: tx.tx_id,
+                    min_withdrawal,
+                })
+            }
+            _ => {}
+        }
+        if let (Some((client_id, amount)), Some(max_balance)) =
+            (self.credit(tx), self.config.max_balance)
+        {
+            let total = self
+                .client_accounts
+                .get(&client_id)
+                .map_or(dec!(0), |account| account.lock().total());
+            if total
+                .checked_add(amount)
+                .is_none_or(|total| total > max_balance)
+            {
+                return Err(EngineError::MaxBalanceExceeded {
+                    client_id,
+                    tx_id: tx.tx_id,
+                    max_balance,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // The account a deposit or a fee credits, and by how much
+    fn credit(&self, tx: &Tx) -> Option<(ClientId, Amount)> {
+        match (&tx.inner, self.fee_account) {
+            (TxInner::Deposit { amount }, _) => Some((tx.client_id, *amount)),
+            (TxInner::Fee { amount }, Some(fee_account)) => Some((fee_account, *amount)),
+            _ => None,
+        }
+    }
+
+    fn audit(&mut self, tx: &Tx, outcome: AuditOutcome) {
+        if let Some(sink) = &mut self.audit_sink {
+            sink.record(tx, outcome);
+        }
+    }
+
+    fn can_process_tx(&self, tx: &Tx) -> Result<(), IgnoreReason> {
+        if tx.client_id == RESERVED_CLIENT_ID {
+            return Err(IgnoreReason::InvalidClientId);
+        }
+        if self.daily_volume_exceeded(tx) {
+            return Err(IgnoreReason::DailyVolumeCapReached);
+        }
+        if !self.valid_amount(tx) {
+            return Err(IgnoreReason::InvalidAmount);
+        }
+        if self.client_account_frozen(tx) {
+            return Err(IgnoreReason::AccountLocked);
+        }
+        self.check_referenced_tx(tx)?;
+        if self.duplicate_tx(tx) {
+            return Err(IgnoreReason::DuplicateTxId);
+        }
+        if !self.sufficient_funds(tx) {
+            return Err(IgnoreReason::InsufficientFunds);
+        }
+        if self.balance_would_overflow(tx) {
+            return Err(IgnoreReason::BalanceOverflow);
+        }
+        if !self.recipient_can_receive(tx) {
+            return Err(IgnoreReason::InvalidRecipient);
+        }
+        if self.currency_mismatch(tx) {
+            return Err(IgnoreReason::CurrencyMismatch);
+        }
+        if !self.exchange_rate_accepted(tx) {
+            return Err(IgnoreReason::InvalidExchangeRate);
+        }
+        if self.velocity_exceeded(tx) {
+            return Err(IgnoreReason::VelocityExceeded);
+        }
+        if self.fraud_suspected(tx) {
+            return Err(IgnoreReason::FraudSuspect);
+        }
+        Ok(())
+    }
+
+    fn valid_amount(&self, tx: &Tx) -> bool {
+        match tx.inner {
+            TxInner::Deposit { amount }
+            | TxInner::Withdrawal { amount }
+            | TxInner::Transfer { amount, .. }
+            | TxInner::Fee { amount }
+            | TxInner::FxTransfer { amount, .. } => amount > dec!(0),
+            _ => true,
+        }
+    }
+
+    fn check_referenced_tx(&self, tx: &Tx) -> Result<(), IgnoreReason> {
+        if !tx.inner.references_prior_tx() {
+            return Ok(());
+        }
+        if self.referenced_amount(tx).is_none() {
+            return Err(IgnoreReason::UnknownTx);
+        }
+        let disputed = self.disputed_txs.lock().contains(&tx.tx_id);
+        match (&tx.inner, disputed) {
+            (TxInner::Dispute, true) => Err(IgnoreReason::AlreadyDisputed),
+            (TxInner::Resolve, false) | (TxInner::Chargeback, false) => {
+                Err(IgnoreReason::NotDisputed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn duplicate_tx(&self, tx: &Tx) -> bool {
+        !tx.inner.references_prior_tx() && self.done_txs.lock().contains_key(&tx.tx_id)
+    }
+
+    fn client_account_frozen(&self, tx: &Tx) -> bool {
+        // Fees are administrative and still apply to locked accounts
+        if let TxInner::Fee { .. } = tx.inner {
+            return false;
+        }
+        matches!(self.client_accounts.get(&tx.client_id),
+            Some(account) if account.lock().locked)
+    }
+
+    fn sufficient_funds(&self, tx: &Tx) -> bool {
+        let amount = match tx.inner {
+            TxInner::Withdrawal { amount }
+            | TxInner::Transfer { amount, .. }
+            | TxInner::Fee { amount }
+            | TxInner::FxTransfer { amount, .. } => amount,
+            // Holding more than is available would take the account negative
+            TxInner::Dispute => match self.referenced_amount(tx) {
+                Some(amount) => amount,
+                None => return true,
+            },
+            _ => return true,
+        };
+        match self.client_accounts.get(&tx.client_id) {
+            Some(account) => {
+                let available = account.lock().available;
+                if available >= amount {
+                    return true;
+                }
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    client_id = tx.client_id,
+                    tx_id = tx.tx_id,
+                    %available,
+                    required = %amount,
+                    "insufficient funds"
+                );
+                false
+            }
+            None => {
+                // Usually a debit arriving before the client's first deposit
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    client_id = tx.client_id,
+                    tx_id = tx.tx_id,
+                    "debit for unknown client account"
+                );
+                false
+            }
+        }
+    }
+
+    fn balance_would_overflow(&self, tx: &Tx) -> bool {
+        let (debited, credit) = match tx.inner {
+            TxInner::Deposit { amount } => (dec!(0), Some((tx.client_id, amount))),
+            TxInner::Withdrawal { amount } => (amount, None),
+            TxInner::Transfer {
+                to_client_id,
+                amount,
+            } => (amount, Some((to_client_id, amount))),
+            TxInner::Fee { amount } => (amount, self.fee_account.map(|id| (id, amount))),
+            TxInner::FxTransfer {
+                to_client,
+                amount,
+                rate,
+                ..
+            } => match amount.checked_mul(rate) {
+                Some(credited) => (amount, Some((to_client, credited.round_dp(4)))),
+                None => return true,
+            },
+            _ => return false,
+        };
+        if matches!(self.client_accounts.get(&tx.client_id),
+            Some(account) if !account.lock().can_debit(debited))
+        {
+            return true;
+        }
+        let (to_client_id, credited) = match credit {
+            Some(credit) => credit,
+            None => return false,
+        };
+        if matches!(self.client_accounts.get(&to_client_id),
+            Some(account) if !account.lock().can_credit(credited))
+        {
+            return true;
+        }
+        // Only deposits, and fx transfers crediting more than they debit,
+        // add to the engine's total
+        !self.engine_total_can_grow(credited - debited)
+    }
+
+    fn engine_total_can_grow(&self, amount: Amount) -> bool {
+        amount <= dec!(0)
+            || (self.running_total_available + self.running_total_held)
+                .checked_add(amount)
+                .is_some()
+    }
+
+    fn recipient_can_receive(&self, tx: &Tx) -> bool {
+        let to_client_id = match tx.inner {
+            TxInner::Transfer { to_client_id, .. } => to_client_id,
+            TxInner::FxTransfer { to_client, .. } => to_client,
+            _ => return true,
+        };
+        if to_client_id == tx.client_id || to_client_id == RESERVED_CLIENT_ID {
+            return false;
+        }
+        let sender_currency = match self.client_accounts.get(&tx.client_id) {
+            Some(sender) => sender.lock().currency,
+            None => return false,
+        };
+        match self.client_accounts.get(&to_client_id) {
+            Some(recipient) => {
+                let recipient = recipient.lock();
+                // Transfers between currencies have to go through FxTransfer,
+                // whose currencies are checked by currency_mismatch
+                !recipient.locked
+                    && (matches!(tx.inner, TxInner::FxTransfer { .. })
+                        || recipient.currency == sender_currency)
+            }
+            None => true,
+        }
+    }
+
+    fn currency_mismatch(&self, tx: &Tx) -> bool {
+        let (to_client, from_currency, to_currency) = match tx.inner {
+            TxInner::FxTransfer {
+                to_client,
+                from_currency,
+                to_currency,
+                ..
+            } => (to_client, from_currency, to_currency),
+            _ => return false,
+        };
+        let currency_of = |client_id| {
+            self.client_accounts
+                .get(&client_id)
+                .map(|account| account.lock().currency)
+        };
+        currency_of(tx.client_id).is_some_and(|currency| currency != from_currency)
+            || currency_of(to_client).is_some_and(|currency| currency != to_currency)
+    }
+
+    fn exchange_rate_accepted(&self, tx: &Tx) -> bool {
+        let (amount, from_currency, to_currency, rate) = match tx.inner {
+            TxInner::FxTransfer {
+                amount,
+                from_currency,
+                to_currency,
+                rate,
+                ..
+            } => (amount, from_currency, to_currency, rate),
+            _ => return true,
+        };
+        if rate <= dec!(0) {
+            return false;
+        }
+        // The agreed rate may deviate from the provider's current rate by at most 0.01%
+        let credited = (amount * rate).round_dp(4);
+        match self.fx_convert(amount, from_currency, to_currency) {
+            Ok(expected) => (credited - expected).abs() <= expected * dec!(0.0001),
+            Err(_) => false,
+        }
+    }
+
+    fn daily_volume_exceeded(&self, tx: &Tx) -> bool {
+        match (&self.daily_volume, &tx.inner) {
+            (Some(limit), _) if limit.halted => true,
+            (Some(limit), TxInner::Deposit { amount })
+            | (Some(limit), TxInner::Withdrawal { amount }) => limit
+                .volume
+                .checked_add(*amount)
+                .is_none_or(|volume| volume > limit.max_daily_volume),
+            _ => false,
+        }
+    }
+
+    fn record_daily_volume(&mut self, tx: &Tx) {
+        if let (Some(limit), TxInner::Deposit { amount })
+        | (Some(limit), TxInner::Withdrawal { amount }) = (&mut self.daily_volume, &tx.inner)
+        {
+            limit.volume += *amount;
+            if limit.volume >= limit.max_daily_volume {
+                self.halt_for_the_day();
+            }
+        }
+    }
+
+    fn halt_for_the_day(&mut self) {
+        let alert = match &mut self.daily_volume {
+            Some(limit) if !limit.halted => {
+                limit.halted = true;
+                Alert::DailyVolumeCapReached {
+                    date: limit
+                        .day
+                        .expect("daily volume limit is rolled over before every check"),
+                    volume: limit.volume,
+                    limit: limit.max_daily_volume,
+                }
+            }
+            _ => return,
+        };
+        self.emit_alert(alert);
+    }
+
+    fn velocity_amount(tx: &Tx) -> Option<Amount> {
+        match tx.inner {
+            TxInner::Deposit { amount }
+            | TxInner::Withdrawal { amount }
+            | TxInner::Transfer { amount, .. }
+            | TxInner::FxTransfer { amount, .. } => Some(amount),
+            _ => None,
+        }
+    }
+
+    fn velocity_exceeded(&self, tx: &Tx) -> bool {
+        let (limit, amount) = match (self.velocity_limit, Self::velocity_amount(tx)) {
+            (Some(limit), Some(amount)) => (limit, amount),
+            _ => return false,
+        };
+        let window_start = self.now() - Duration::hours(1);
+        let recent: Amount = self
+            .velocity_windows
+            .get(&tx.client_id)
+            .map_or(dec!(0.0), |window| {
+                window
+                    .iter()
+                    .filter(|(time, _, _)| *time > window_start)
+                    .map(|(_, _, amount)| *amount)
+                    .sum()
+            });
+        recent
+            .checked_add(amount)
+            .is_none_or(|recent| recent > limit)
+    }
+
+    fn record_velocity(&mut self, tx: &Tx, now: DateTime<Utc>) {
+        let amount = match (self.velocity_limit, Self::velocity_amount(tx)) {
+            (Some(_), Some(amount)) => amount,
+            _ => return,
+        };
+        let window = self.velocity_windows.entry(tx.client_id).or_default();
+        let window_start = now - Duration::hours(1);
+        while matches!(window.front(), Some(&(time, _, _)) if time <= window_start) {
+            window.pop_front();
+        }
+        window.push_back((now, tx.tx_id, amount));
+    }
+
+    fn fraud_suspected(&self, tx: &Tx) -> bool {
+        match &self.fraud_scorer {
+            Some((scorer, threshold)) => {
+                let score = scorer.score(tx, &self.account_context(tx.client_id));
+                if score <= *threshold {
+                    return false;
+                }
+                self.emit_alert(Alert::FraudScoreExceeded {
+                    client_id: tx.client_id,
+                    tx_id: tx.tx_id,
+                    score,
+                    threshold: *threshold,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Internal transactions count down from the top of the id space to stay
+    // clear of the ids clients assign
+    fn next_internal_tx_id(&mut self) -> TxId {
+        let done_txs = self.done_txs.lock();
+        loop {
+            let tx_id = TxId::MAX - self.internal_tx_count;
+            self.internal_tx_count += 1;
+            if !done_txs.contains_key(&tx_id) {
+                return tx_id;
+            }
+        }
+    }
+
+    fn update_tx_history(&mut self, tx: Tx) -> Result<(), EngineError> {
+        if let std::collections::hash_map::Entry::Vacant(e) = self.done_txs.lock().entry(tx.tx_id) {
+            e.insert(tx);
+            Ok(())
+        } else {
+            Err(EngineError::TxAlreadyRecorded(tx.tx_id))
+        }
+    }
+
+    fn chargeback_amount(
+        &self,
+        client_id: ClientId,
+        tx_id: TxId,
+    ) -> Result<Amount, ChargebackRejection> {
+        let done_txs = self.done_txs.lock();
+        let tx = done_txs
+            .get(&tx_id)
+            .ok_or(ChargebackRejection::TxNotFound)?;
+        if tx.client_id != client_id {
+            return Err(ChargebackRejection::WrongClient);
+        }
+        if !self.disputed_txs.lock().contains(&tx_id) {
+            return Err(ChargebackRejection::NotDisputed);
+        }
+        match tx.inner {
+            TxInner::Deposit { amount } | TxInner::Withdrawal { amount } => Ok(amount),
+            _ => Err(ChargebackRejection::NotDisputed),
+        }
+    }
+
+    fn referenced_amount(&self, tx: &Tx) -> Option<Amount> {
+        let done_txs = self.done_txs.lock();
+        let referenced_tx = done_txs.get(&tx.tx_id)?;
+        // A client may only dispute their own transactions
+        if referenced_tx.client_id != tx.client_id {
+            return None;
+        }
+        match referenced_tx.inner {
+            TxInner::Withdrawal { amount } | TxInner::Deposit { amount } => Some(amount),
+            _ => None,
+        }
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, tx), fields(tx_id = tx.tx_id, client_id = tx.client_id))
+    )]
+    fn dispute(&mut self, tx: &Tx) {
+        // Checked by can_process_tx, so this only guards the balances
+        if self.disputed_txs.lock().contains(&tx.tx_id) {
+            return;
+        }
+        if let Some(amount) = self.referenced_amount(tx) {
+            if let Some(client) = self.client_accounts.get(&tx.client_id) {
+                client.lock().dispute(amount);
+                self.running_total_available -= amount;
+                self.running_total_held += amount;
+                self.disputed_txs.lock().insert(tx.tx_id);
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
+    fn deposit(&mut self, client_id: ClientId, amount: Amount) {
+        self.client_accounts
+            .entry(client_id)
+            .or_insert_with(|| Arc::new(Mutex::new(ClientAccount::new())))
+            .lock()
+            .deposit(amount);
+        self.running_total_available += amount;
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
+    fn withdrawal(&mut self, client_id: ClientId, amount: Amount) {
+        self.client_accounts
+            .entry(client_id)
+            .or_insert_with(|| Arc::new(Mutex::new(ClientAccount::new())))
+            .lock()
+            .withdrawal(amount);
+        self.running_total_available -= amount;
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, tx), fields(tx_id = tx.tx_id, client_id = tx.client_id))
+    )]
+    fn resolve(&mut self, tx: &Tx) {
+        if !self.disputed_txs.lock().contains(&tx.tx_id) {
+            return;
+        }
+        if let Some(amount) = self.referenced_amount(tx) {
+            if let Some(client) = self.client_accounts.get(&tx.client_id) {
+                client.lock().resolve(amount);
+                self.running_total_available += amount;
+                self.running_total_held -= amount;
+                self.disputed_txs.lock().remove(&tx.tx_id);
+            }
+        }
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, tx), fields(tx_id = tx.tx_id, client_id = tx.client_id))
+    )]
+    fn chargeback(&mut self, tx: &Tx) {
+        if !self.disputed_txs.lock().contains(&tx.tx_id) {
+            return;
+        }
+        if let Some(amount) = self.referenced_amount(tx) {
+            if let Some(client) = self.client_accounts.get(&tx.client_id) {
+                client.lock().chargeback(amount);
+                self.running_total_held -= amount;
+                self.disputed_txs.lock().remove(&tx.tx_id);
+                self.charged_back_txs.lock().insert(tx.tx_id);
+            }
+        }
+    }
+
+    fn fee(&mut self, tx: &Tx, amount: Amount) {
+        self.withdrawal(tx.client_id, amount);
+        if let Some(fee_account) = self.fee_account {
+            self.deposit(fee_account, amount);
+            self.audit_log.push(AuditEvent::FeeCredited {
+                tx_id: tx.tx_id,
+                from_client: tx.client_id,
+                fee_account,
+                amount,
+            });
+        }
+    }
+
+    fn transfer(&mut self, tx: &Tx, to_client_id: ClientId, amount: Amount) {
+        // Checked by can_process_tx, so the sender's account exists
+        let currency = self.client_accounts[&tx.client_id].lock().currency;
+        self.withdrawal(tx.client_id, amount);
+        self.client_accounts
+            .entry(to_client_id)
+            .or_insert_with(|| Arc::new(Mutex::new(ClientAccount::with_currency(currency))))
+            .lock()
+            .deposit(amount);
+        self.running_total_available += amount;
+    }
+
+    fn fx_transfer(
+        &mut self,
+        tx: &Tx,
+        to_client: ClientId,
+        amount: Amount,
+        from_currency: Currency,
+        to_currency: Currency,
+        rate: Decimal,
+    ) {
+        // Checked by can_process_tx, so the sender's account exists and
+        // the rate is acceptable
+        let credited = (amount * rate).round_dp(4);
+        self.withdrawal(tx.client_id, amount);
+        self.client_accounts
+            .entry(to_client)
+            .or_insert_with(|| Arc::new(Mutex::new(ClientAccount::with_currency(to_currency))))
+            .lock()
+            .deposit(credited);
+        self.running_total_available += credited;
+        self.audit_log.push(AuditEvent::FxTransfer {
+            tx_id: tx.tx_id,
+            from_client: tx.client_id,
+            to_client,
+            debited: amount,
+            credited,
+            from_currency,
+            to_currency,
+            rate,
+        });
+    }
+
+    fn update_client_accounts(&mut self, tx: &Tx) {
+        match tx.inner {
+            TxInner::Deposit { amount } => self.deposit(tx.client_id, amount),
             TxInner::Withdrawal { amount } => self.withdrawal(tx.client_id, amount),
             TxInner::Dispute => self.dispute(tx),
             TxInner::Resolve => self.resolve(tx),
             TxInner::Chargeback => self.chargeback(tx),
+            TxInner::Transfer {
+                to_client_id,
+                amount,
+            } => self.transfer(tx, to_client_id, amount),
+            TxInner::Fee { amount } => self.fee(tx, amount),
+            TxInner::FxTransfer {
+                to_client,
+                amount,
+                from_currency,
+                to_currency,
+                rate,
+            } => self.fx_transfer(tx, to_client, amount, from_currency, to_currency, rate),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::fx::MockExchangeRateProvider;
+    use proptest::prelude::*;
+    use std::ops::Range;
+
+    #[derive(Debug, Clone)]
+    enum AccountOp {
+        Deposit(Amount),
+        Withdrawal(Amount),
+        Dispute(usize),
+        Resolve(usize),
+        Chargeback(usize),
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum DepositState {
+        Settled,
+        Disputed,
+    }
+
+    fn amount() -> impl Strategy<Value = Amount> {
+        (1i64..1_000_000_000).prop_map(|n| Amount::new(n, 4))
+    }
+
+    fn account_op() -> impl Strategy<Value = AccountOp> {
+        prop_oneof![
+            amount().prop_map(AccountOp::Deposit),
+            amount().prop_map(AccountOp::Withdrawal),
+            any::<usize>().prop_map(AccountOp::Dispute),
+            any::<usize>().prop_map(AccountOp::Resolve),
+            any::<usize>().prop_map(AccountOp::Chargeback),
+        ]
+    }
+
+    // Maps an arbitrary index onto one of the deposits currently in `state`,
+    // so that only valid transitions are ever applied
+    fn pick(deposits: &[(Amount, DepositState)], state: DepositState, i: usize) -> Option<usize> {
+        let candidates: Vec<usize> = (0..deposits.len())
+            .filter(|&j| deposits[j].1 == state)
+            .collect();
+        if candidates.is_empty() {
+            None
+        } else {
+            Some(candidates[i % candidates.len()])
+        }
+    }
+
+    #[test]
+    fn total_includes_held_funds() {
+        let mut account = ClientAccount::new();
+        account.deposit(dec!(10.0));
+        account.deposit(dec!(2.5));
+        account.dispute(dec!(2.5));
+        assert_eq!(account.available, dec!(10.0));
+        assert_eq!(account.held, dec!(2.5));
+        assert_eq!(account.total(), dec!(12.5));
+        account.withdrawal(dec!(4.0));
+        assert_eq!(account.total(), dec!(8.5));
+    }
+
+    #[test]
+    fn lifetime_totals_track_deposits_and_withdrawals() {
+        let mut account = ClientAccount::new();
+        account.deposit(dec!(10.0));
+        account.deposit(dec!(2.5));
+        account.withdrawal(dec!(4.0));
+        account.dispute(dec!(2.5));
+        account.chargeback(dec!(2.5));
+        assert_eq!(account.total_deposited(), dec!(12.5));
+        assert_eq!(account.total_withdrawn(), dec!(4.0));
+        assert_eq!(account.net_flow(), dec!(8.5));
+        account.undo_withdrawal(dec!(4.0));
+        assert_eq!(account.total_withdrawn(), dec!(0.0));
+    }
+
+    #[test]
+    fn resolve_undoes_dispute() {
+        let mut account = ClientAccount::new();
+        account.deposit(dec!(3.0));
+        let before = account.clone();
+        account.dispute(dec!(1.0));
+        assert_ne!(account, before);
+        account.resolve(dec!(1.0));
+        assert_eq!(account, before);
+    }
+
+    fn deposit(client_id: ClientId, tx_id: TxId, amount: Amount) -> Tx {
+        Tx {
+            client_id,
+            tx_id,
+            inner: TxInner::Deposit { amount },
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_report_yields_rows_in_client_order() {
+        let txs = vec![
+            deposit(3, 1, dec!(1.0)),
+            deposit(1, 2, dec!(2.0)),
+            deposit(2, 3, dec!(3.0)),
+        ];
+        let mut engine = PaymentsEngine::new(tokio_stream::iter(txs));
+        engine.process_txs().await.expect("failed to process txs");
+        let streamed: Vec<ReportRow> = engine.stream_report().collect().await;
+        assert_eq!(
+            streamed.iter().map(|row| row.client_id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(streamed, engine.report_rows());
+    }
+
+    #[tokio::test]
+    async fn rollback_tx_reverses_deposits_and_withdrawals() {
+        let txs = vec![
+            deposit(1, 1, dec!(5.0)),
+            Tx {
+                client_id: 1,
+                tx_id: 2,
+                inner: TxInner::Withdrawal { amount: dec!(2.0) },
+            },
+            deposit(1, 3, dec!(1.0)),
+            Tx {
+                client_id: 1,
+                tx_id: 3,
+                inner: TxInner::Dispute,
+            },
+        ];
+        let mut engine = PaymentsEngine::new(tokio_stream::iter(txs));
+        engine.process_txs().await.expect("failed to process txs");
+
+        assert_eq!(engine.rollback_tx(3), Err(EngineError::TxInDispute(3)));
+        assert_eq!(engine.rollback_tx(4), Err(EngineError::TxNotFound(4)));
+        // Undoing the withdrawal first leaves enough to undo the deposit
+        assert_eq!(
+            engine.rollback_tx(1),
+            Err(EngineError::InsufficientFunds(1))
+        );
+        assert_eq!(engine.rollback_tx(2), Ok(()));
+        assert_eq!(engine.rollback_tx(1), Ok(()));
+        assert_eq!(engine.rollback_tx(1), Err(EngineError::TxNotFound(1)));
+
+        let row = &engine.report_rows()[0];
+        assert_eq!(row.available, dec!(0.0));
+        assert_eq!(row.held, dec!(1.0));
+        assert_eq!(engine.transaction_count(), 1);
+    }
+
+    #[test]
+    fn negative_deposits_and_withdrawals_are_ignored() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        let results = engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(5.0)),
+            deposit(1, 2, dec!(-100.0)),
+            withdrawal(1, 3, dec!(-100.0)),
+        ]);
+        for (tx_id, result) in (2..).zip(&results[1..]) {
+            assert_eq!(
+                *result,
+                Err(EngineError::Ignored {
+                    tx_id,
+                    reason: IgnoreReason::InvalidAmount,
+                })
+            );
+        }
+        let row = &engine.report_rows()[0];
+        assert_eq!((row.available, row.total), (dec!(5.0), dec!(5.0)));
+    }
+
+    #[test]
+    fn dispute_for_more_than_available_funds_is_ignored() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        let results = engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(5.0)),
+            withdrawal(1, 2, dec!(4.0)),
+            Tx {
+                client_id: 1,
+                tx_id: 1,
+                inner: TxInner::Dispute,
+            },
+        ]);
+        assert_eq!(
+            results[2],
+            Err(EngineError::Ignored {
+                tx_id: 1,
+                reason: IgnoreReason::InsufficientFunds,
+            })
+        );
+        let row = &engine.report_rows()[0];
+        assert_eq!(
+            (row.available, row.held, row.total),
+            (dec!(1.0), dec!(0.0), dec!(1.0))
+        );
+    }
+
+    #[test]
+    fn txs_that_would_overflow_a_balance_are_ignored() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        let results = engine.process_txs_batch(vec![
+            deposit(1, 1, Decimal::MAX),
+            deposit(1, 2, dec!(1.0)),
+            // The balance fits after a withdrawal, but the deposited total doesn't
+            withdrawal(1, 3, dec!(1.0)),
+            deposit(1, 4, dec!(1.0)),
+            // Each balance fits, but the engine's total doesn't
+            deposit(2, 5, Decimal::MAX),
+        ]);
+        for (tx_id, result) in [(2, &results[1]), (4, &results[3]), (5, &results[4])] {
+            assert_eq!(
+                *result,
+                Err(EngineError::Ignored {
+                    tx_id,
+                    reason: IgnoreReason::BalanceOverflow,
+                })
+            );
+        }
+        assert_eq!(engine.report_rows()[0].total, Decimal::MAX - dec!(1));
+        assert_eq!(engine.report_rows().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_applies_queued_txs_and_refuses_new_ones() {
+        let (sender, receiver) = channel(10);
+        let mut engine = PaymentsEngine::new(ReceiverStream::new(receiver));
+        sender.send(deposit(1, 1, dec!(1.0))).await.unwrap();
+        sender.send(withdrawal(2, 2, dec!(1.0))).await.unwrap();
+
+        let summary = engine
+            .graceful_shutdown(std::time::Duration::from_secs(1))
+            .await
+            .expect("shutdown timed out");
+        assert_eq!(
+            summary,
+            ProcessingSummary {
+                transactions_accepted: 1,
+                transactions_ignored: 1,
+                clients: 1,
+                #[cfg(feature = "bench")]
+                update_timings: summary.update_timings,
+            }
+        );
+        assert!(sender.send(deposit(1, 3, dec!(1.0))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn warm_up_leaves_tx_ids_free() {
+        let mut engine = PaymentsEngine::new(tokio_stream::iter(vec![deposit(1, 1, dec!(1.0))]));
+        engine
+            .warm_up(vec![deposit(1, 1, dec!(10.0)), deposit(2, 2, dec!(3.0))].into_iter())
+            .expect("failed to warm up");
+        assert_eq!(engine.transaction_count(), 0);
+        assert!(engine
+            .warm_up(
+                vec![Tx {
+                    client_id: 2,
+                    tx_id: 3,
+                    inner: TxInner::Withdrawal { amount: dec!(5.0) },
+                }]
+                .into_iter()
+            )
+            .is_err());
+
+        engine.process_txs().await.expect("failed to process txs");
+        let available: Vec<Amount> = engine
+            .report_rows()
+            .iter()
+            .map(|row| row.available)
+            .collect();
+        assert_eq!(available, vec![dec!(11.0), dec!(3.0)]);
+        assert_eq!(engine.transaction_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn process_txs_from_file_through_a_small_channel() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("transactions.csv");
+        let mut engine = PaymentsEngineBuilder::new()
+            .channel_size(1)
+            .build(tokio_stream::empty());
+        engine
+            .process_txs_from_file(&[path])
+            .await
+            .expect("failed to process file");
+        assert_eq!(engine.client_count(), 2);
+    }
+
+    fn engine_with_max_balance(max_balance: Option<Amount>) -> PaymentsEngine<Empty<Tx>> {
+        let builder = PaymentsEngineBuilder::new();
+        let builder = match max_balance {
+            Some(max_balance) => builder.max_balance(max_balance),
+            None => builder,
+        };
+        let mut engine = builder.build(tokio_stream::empty());
+        engine.update(deposit(1, 1, dec!(6.0))).unwrap();
+        engine
+    }
+
+    #[test]
+    fn max_balance_accepts_deposit_up_to_the_limit() {
+        let mut engine = engine_with_max_balance(Some(dec!(10.0)));
+        let tx = deposit(1, 2, dec!(4.0));
+        assert_eq!(engine.check_limits(&tx), Ok(()));
+        engine.update(tx).unwrap();
+        assert_eq!(engine.report_rows()[0].total, dec!(10.0));
+    }
+
+    #[test]
+    fn max_balance_rejects_deposit_over_the_limit() {
+        let mut engine = engine_with_max_balance(Some(dec!(10.0)));
+        let tx = deposit(1, 2, dec!(4.0001));
+        assert_eq!(
+            engine.check_limits(&tx),
+            Err(EngineError::MaxBalanceExceeded {
+                client_id: 1,
+                tx_id: 2,
+                max_balance: dec!(10.0),
+            })
+        );
+        engine.update(tx).unwrap();
+        assert_eq!(engine.report_rows()[0].total, dec!(6.0));
+        assert_eq!(
+            engine.ignored_counts()[&IgnoreReason::MaxBalanceExceeded],
+            1
+        );
+        assert_eq!(engine.transaction_count(), 1);
+    }
+
+    #[test]
+    fn no_max_balance_accepts_any_deposit() {
+        let mut engine = engine_with_max_balance(None);
+        let tx = deposit(1, 2, dec!(1000000000.0));
+        assert_eq!(engine.check_limits(&tx), Ok(()));
+        engine.update(tx).unwrap();
+        assert_eq!(engine.report_rows()[0].total, dec!(1000000006.0));
+    }
+
+    fn withdrawal(client_id: ClientId, tx_id: TxId, amount: Amount) -> Tx {
+        Tx {
+            client_id,
+            tx_id,
+            inner: TxInner::Withdrawal { amount },
+        }
+    }
+
+    #[test]
+    fn minimum_amounts_are_inclusive() {
+        let engine = PaymentsEngineBuilder::new()
+            .min_deposit(dec!(1.0))
+            .min_withdrawal(dec!(0.5))
+            .build(tokio_stream::empty());
+        assert_eq!(engine.check_limits(&deposit(1, 1, dec!(1.0))), Ok(()));
+        assert_eq!(
+            engine.check_limits(&deposit(1, 2, dec!(0.9999))),
+            Err(EngineError::BelowMinimumDeposit {
+                tx_id: 2,
+                min_deposit: dec!(1.0),
+            })
+        );
+        assert_eq!(engine.check_limits(&withdrawal(1, 3, dec!(0.5))), Ok(()));
+        assert_eq!(
+            engine.check_limits(&withdrawal(1, 4, dec!(0.4999))),
+            Err(EngineError::BelowMinimumWithdrawal {
+                tx_id: 4,
+                min_withdrawal: dec!(0.5),
+            })
+        );
+    }
+
+    #[test]
+    fn withdrawal_below_minimum_is_told_apart_from_insufficient_funds() {
+        let mut engine = PaymentsEngineBuilder::new()
+            .min_withdrawal(dec!(0.5))
+            .build(tokio_stream::empty());
+        engine.update(deposit(1, 1, dec!(1.0))).unwrap();
+        engine.update(withdrawal(1, 2, dec!(0.1))).unwrap();
+        engine.update(withdrawal(1, 3, dec!(5.0))).unwrap();
+        assert_eq!(
+            engine.ignored_counts()[&IgnoreReason::BelowMinimumWithdrawal],
+            1
+        );
+        assert_eq!(engine.ignored_counts()[&IgnoreReason::InsufficientFunds], 1);
+        assert_eq!(engine.report_rows()[0].available, dec!(1.0));
+    }
+
+    #[tokio::test]
+    async fn process_txs_from_in_memory_reader() {
+        let input = "type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,0.5\n";
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        engine
+            .process_txs_from_reader(std::io::Cursor::new(input.as_bytes().to_vec()))
+            .await
+            .expect("failed to process input");
+        assert_eq!(engine.report_rows()[0].available, dec!(1.5));
+    }
+
+    #[tokio::test]
+    async fn amount_precision_applies_to_engine_input() {
+        let input =
+            "type,client,tx,amount\ndeposit,1,1,1.123\ndeposit,1,2,1.1234\ndeposit,1,3,1.12345\n";
+
+        let mut strict = PaymentsEngineBuilder::new()
+            .amount_precision(AmountPrecision::Strict)
+            .build(tokio_stream::empty());
+        assert!(strict.process_txs_from_csv_str(input).await.is_err());
+        assert_eq!(strict.report_rows()[0].total, dec!(2.2464));
+
+        let mut lenient = PaymentsEngine::new(tokio_stream::empty());
+        lenient
+            .process_txs_from_csv_str(input)
+            .await
+            .expect("failed to process input");
+        assert_eq!(lenient.report_rows()[0].total, dec!(3.3698));
+    }
+
+    #[test]
+    fn process_txs_batch_returns_one_result_per_tx() {
+        let mut engine = PaymentsEngineBuilder::new()
+            .min_deposit(dec!(1.0))
+            .build(tokio_stream::empty());
+        let results = engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(2.0)),
+            withdrawal(1, 2, dec!(5.0)),
+            deposit(1, 3, dec!(0.5)),
+        ]);
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Err(EngineError::Ignored {
+                    tx_id: 2,
+                    reason: IgnoreReason::InsufficientFunds
+                }),
+                Err(EngineError::BelowMinimumDeposit {
+                    tx_id: 3,
+                    min_deposit: dec!(1.0)
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn process_one_tx_reports_ignore_reason() {
+        let mut engine = PaymentsEngineBuilder::new()
+            .min_withdrawal(dec!(1.0))
+            .build(tokio_stream::empty());
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        assert_eq!(outcome(deposit(1, 1, dec!(2.0))), TxOutcome::Applied);
+        assert_eq!(
+            outcome(deposit(1, 1, dec!(2.0))),
+            TxOutcome::Ignored(IgnoreReason::DuplicateTxId)
+        );
+        assert_eq!(
+            outcome(withdrawal(1, 2, dec!(0.5))),
+            TxOutcome::Ignored(IgnoreReason::BelowMinimumWithdrawal)
+        );
+        assert_eq!(
+            outcome(withdrawal(1, 3, dec!(5.0))),
+            TxOutcome::Ignored(IgnoreReason::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn process_one_tx_ignores_disputes_it_cannot_apply() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        let tx = |client_id, tx_id, inner| Tx {
+            client_id,
+            tx_id,
+            inner,
+        };
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        outcome(deposit(1, 1, dec!(5.0)));
+        outcome(deposit(2, 2, dec!(5.0)));
+        outcome(withdrawal(1, 3, dec!(4.0)));
+
+        let ignored = [
+            (tx(1, 9, TxInner::Dispute), IgnoreReason::UnknownTx),
+            (tx(1, 2, TxInner::Dispute), IgnoreReason::UnknownTx),
+            (tx(1, 1, TxInner::Resolve), IgnoreReason::NotDisputed),
+            (tx(1, 1, TxInner::Chargeback), IgnoreReason::NotDisputed),
+            (tx(1, 1, TxInner::Dispute), IgnoreReason::InsufficientFunds),
+        ];
+        for (tx, reason) in ignored.iter().cloned() {
+            assert_eq!(outcome(tx.clone()), TxOutcome::Ignored(reason), "{}", tx);
+        }
+        outcome(deposit(1, 4, dec!(5.0)));
+        assert_eq!(outcome(tx(1, 1, TxInner::Dispute)), TxOutcome::Applied);
+        assert_eq!(
+            outcome(tx(1, 1, TxInner::Dispute)),
+            TxOutcome::Ignored(IgnoreReason::AlreadyDisputed)
+        );
+        assert_eq!(outcome(tx(1, 1, TxInner::Resolve)), TxOutcome::Applied);
+
+        let stats = engine.statistics();
+        assert_eq!(stats.dispute_count, 1);
+        assert_eq!(stats.resolve_count, 1);
+        assert_eq!(stats.chargeback_count, 0);
+        assert_eq!(stats.ignored_by_reason[&IgnoreReason::UnknownTx], 2);
+    }
+
+    fn fx_transfer(
+        client_id: ClientId,
+        tx_id: TxId,
+        to_client: ClientId,
+        amount: Amount,
+        rate: Decimal,
+    ) -> Tx {
+        Tx {
+            client_id,
+            tx_id,
+            inner: TxInner::FxTransfer {
+                to_client,
+                amount,
+                from_currency: Currency::USD,
+                to_currency: Currency::EUR,
+                rate,
+            },
+        }
+    }
+
+    fn fx_engine() -> PaymentsEngine<Empty<Tx>> {
+        let rates =
+            MockExchangeRateProvider::new().with_rate(Currency::USD, Currency::EUR, dec!(0.9));
+        PaymentsEngine::new(tokio_stream::empty()).with_exchange_rate_provider(Arc::new(rates))
+    }
+
+    #[test]
+    fn fx_transfer_credits_recipient_at_agreed_rate() {
+        let mut engine = fx_engine();
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        assert_eq!(outcome(deposit(1, 1, dec!(10.0))), TxOutcome::Applied);
+        // Within 0.01% of the provider's rate
+        assert_eq!(
+            outcome(fx_transfer(1, 2, 2, dec!(5.0), dec!(0.90005))),
+            TxOutcome::Applied
+        );
+
+        // 4.50025 is rounded half to even
+        let accounts: HashMap<_, _> = engine.accounts().collect();
+        assert_eq!(accounts[&1].available, dec!(5.0));
+        assert_eq!(accounts[&2].available, dec!(4.5002));
+        assert_eq!(accounts[&2].currency(), Currency::EUR);
+        assert_eq!(
+            engine.drain_audit_events(),
+            vec![AuditEvent::FxTransfer {
+                tx_id: 2,
+                from_client: 1,
+                to_client: 2,
+                debited: dec!(5.0),
+                credited: dec!(4.5002),
+                from_currency: Currency::USD,
+                to_currency: Currency::EUR,
+                rate: dec!(0.90005),
+            }]
+        );
+        assert_eq!(engine.processing_summary().transactions_accepted, 2);
+    }
+
+    #[test]
+    fn fx_transfer_is_ignored_before_it_is_counted() {
+        let mut engine = fx_engine();
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        outcome(deposit(1, 1, dec!(10.0)));
+        outcome(deposit(3, 2, dec!(10.0)));
+        // Gives client 4 a EUR account
+        outcome(fx_transfer(3, 3, 4, dec!(1.0), dec!(0.9)));
+        outcome(deposit(3, 4, dec!(1.0)));
+        let locked = Tx {
+            client_id: 3,
+            tx_id: 2,
+            inner: TxInner::Dispute,
+        };
+        outcome(locked.clone());
+        outcome(Tx {
+            inner: TxInner::Chargeback,
+            ..locked
+        });
+
+        let ignored = [
+            (
+                fx_transfer(1, 10, 2, dec!(0.0), dec!(0.9)),
+                IgnoreReason::InvalidAmount,
+            ),
+            (
+                fx_transfer(1, 11, 2, dec!(11.0), dec!(0.9)),
+                IgnoreReason::InsufficientFunds,
+            ),
+            (
+                fx_transfer(1, 12, 1, dec!(1.0), dec!(0.9)),
+                IgnoreReason::InvalidRecipient,
+            ),
+            (
+                fx_transfer(1, 13, 0, dec!(1.0), dec!(0.9)),
+                IgnoreReason::InvalidRecipient,
+            ),
+            (
+                fx_transfer(1, 14, 3, dec!(1.0), dec!(0.9)),
+                IgnoreReason::InvalidRecipient,
+            ),
+            (
+                fx_transfer(4, 15, 2, dec!(0.5), dec!(0.9)),
+                IgnoreReason::CurrencyMismatch,
+            ),
+            (
+                fx_transfer(1, 16, 2, dec!(1.0), dec!(0.0)),
+                IgnoreReason::InvalidExchangeRate,
+            ),
+            (
+                fx_transfer(1, 17, 2, dec!(1.0), dec!(0.95)),
+                IgnoreReason::InvalidExchangeRate,
+            ),
+        ];
+        let accepted = engine.processing_summary().transactions_accepted;
+        for (tx, reason) in ignored.iter().cloned() {
+            assert_eq!(
+                engine
+                    .process_one_tx(tx.clone())
+                    .expect("failed to process tx"),
+                TxOutcome::Ignored(reason),
+                "{}",
+                tx
+            );
+        }
+        assert_eq!(engine.processing_summary().transactions_accepted, accepted);
+        assert!(!engine.accounts().any(|(client_id, _)| client_id == 2));
+        // The ignored ids were never recorded, so they can still be used
+        assert_eq!(
+            engine
+                .process_one_tx(fx_transfer(1, 17, 2, dec!(1.0), dec!(0.9)))
+                .expect("failed to process tx"),
+            TxOutcome::Applied
+        );
+    }
+
+    #[test]
+    fn fx_transfer_needs_a_rate_provider() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        engine
+            .process_one_tx(deposit(1, 1, dec!(10.0)))
+            .expect("failed to process tx");
+        assert_eq!(
+            engine
+                .process_one_tx(fx_transfer(1, 2, 2, dec!(1.0), dec!(0.9)))
+                .expect("failed to process tx"),
+            TxOutcome::Ignored(IgnoreReason::InvalidExchangeRate)
+        );
+    }
+
+    #[test]
+    fn velocity_check_rejects_above_the_hourly_limit() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let mut engine = PaymentsEngine::new(tokio_stream::empty())
+            .with_clock(clock)
+            .with_velocity_check(dec!(10.0));
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        assert_eq!(outcome(deposit(1, 1, dec!(6.0))), TxOutcome::Applied);
+        assert_eq!(
+            outcome(Tx {
+                client_id: 1,
+                tx_id: 2,
+                inner: TxInner::Transfer {
+                    to_client_id: 2,
+                    amount: dec!(3.0),
+                },
+            }),
+            TxOutcome::Applied
+        );
+        // Landing exactly on the limit is allowed
+        assert_eq!(outcome(withdrawal(1, 3, dec!(1.0))), TxOutcome::Applied);
+        assert_eq!(
+            outcome(withdrawal(1, 4, dec!(0.5))),
+            TxOutcome::Ignored(IgnoreReason::VelocityExceeded)
+        );
+        // Incoming transfers don't count toward the recipient's limit
+        assert_eq!(outcome(deposit(2, 5, dec!(7.0))), TxOutcome::Applied);
+    }
+
+    #[test]
+    fn velocity_check_forgets_txs_older_than_an_hour() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let mut engine = PaymentsEngine::new(tokio_stream::empty())
+            .with_clock(clock.clone())
+            .with_velocity_check(dec!(10.0));
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        assert_eq!(outcome(deposit(1, 1, dec!(8.0))), TxOutcome::Applied);
+        clock.advance(Duration::minutes(30));
+        assert_eq!(outcome(deposit(1, 2, dec!(2.0))), TxOutcome::Applied);
+        clock.advance(Duration::minutes(29));
+        assert_eq!(
+            outcome(deposit(1, 3, dec!(1.0))),
+            TxOutcome::Ignored(IgnoreReason::VelocityExceeded)
+        );
+        // The first deposit has now left the window, the second has not
+        clock.advance(Duration::minutes(1));
+        assert_eq!(outcome(deposit(1, 4, dec!(8.0))), TxOutcome::Applied);
+        assert_eq!(
+            outcome(deposit(1, 5, dec!(0.5))),
+            TxOutcome::Ignored(IgnoreReason::VelocityExceeded)
+        );
+    }
+
+    fn daily_volume_engine(
+        clock: Arc<MockClock>,
+    ) -> (PaymentsEngine<Empty<Tx>>, Arc<Mutex<Vec<Alert>>>) {
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&alerts);
+        let engine = PaymentsEngine::new(tokio_stream::empty())
+            .with_clock(clock)
+            .with_daily_volume_limit(dec!(10.0))
+            .on_alert(move |alert| seen.lock().push(alert.clone()));
+        (engine, alerts)
+    }
+
+    fn cap_reached(date: &str, volume: Amount) -> Alert {
+        Alert::DailyVolumeCapReached {
+            date: date.parse().expect("invalid date"),
+            volume,
+            limit: dec!(10.0),
+        }
+    }
+
+    #[test]
+    fn daily_volume_cap_halts_once_reached_until_midnight_utc() {
+        let clock = Arc::new(MockClock::new(
+            "2026-01-01T23:00:00Z".parse().expect("invalid time"),
+        ));
+        let (mut engine, alerts) = daily_volume_engine(Arc::clone(&clock));
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        assert_eq!(outcome(deposit(1, 1, dec!(4.0))), TxOutcome::Applied);
+        assert!(alerts.lock().is_empty());
+        // Landing exactly on the cap is applied, then halts the day
+        assert_eq!(outcome(deposit(1, 2, dec!(6.0))), TxOutcome::Applied);
+        assert_eq!(*alerts.lock(), vec![cap_reached("2026-01-01", dec!(10.0))]);
+        assert_eq!(
+            outcome(deposit(2, 3, dec!(0.5))),
+            TxOutcome::Ignored(IgnoreReason::DailyVolumeCapReached)
+        );
+        assert_eq!(
+            outcome(Tx {
+                client_id: 1,
+                tx_id: 1,
+                inner: TxInner::Dispute,
+            }),
+            TxOutcome::Ignored(IgnoreReason::DailyVolumeCapReached)
+        );
+        assert_eq!(alerts.lock().len(), 1);
+
+        clock.advance(Duration::hours(1));
+        assert_eq!(outcome(deposit(2, 4, dec!(0.5))), TxOutcome::Applied);
+    }
+
+    #[test]
+    fn daily_volume_cap_halts_on_a_tx_that_would_exceed_it() {
+        let clock = Arc::new(MockClock::new(
+            "2026-01-01T12:00:00Z".parse().expect("invalid time"),
+        ));
+        let (mut engine, alerts) = daily_volume_engine(clock);
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        assert_eq!(outcome(deposit(1, 1, dec!(8.0))), TxOutcome::Applied);
+        assert_eq!(
+            outcome(deposit(1, 2, dec!(3.0))),
+            TxOutcome::Ignored(IgnoreReason::DailyVolumeCapReached)
+        );
+        assert_eq!(
+            outcome(deposit(1, 3, dec!(1.0))),
+            TxOutcome::Ignored(IgnoreReason::DailyVolumeCapReached)
+        );
+        assert_eq!(*alerts.lock(), vec![cap_reached("2026-01-01", dec!(8.0))]);
+    }
+
+    #[test]
+    fn ready_once_a_tx_is_accepted() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        assert!(!engine.readiness_check());
+        engine
+            .process_one_tx(withdrawal(1, 1, dec!(1.0)))
+            .expect("failed to process tx");
+        assert!(!engine.readiness_check());
+        engine
+            .process_one_tx(deposit(1, 2, dec!(1.0)))
+            .expect("failed to process tx");
+        assert!(engine.readiness_check());
+        engine
+            .process_one_tx(withdrawal(1, 3, dec!(5.0)))
+            .expect("failed to process tx");
+        assert!(engine.readiness_check());
+        engine.reset();
+        assert!(!engine.readiness_check());
+    }
+
+    #[cfg(feature = "cache")]
+    #[tokio::test]
+    async fn cached_snapshots_are_dropped_when_the_account_changes() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty())
+            .with_realtime_balance_cache(std::time::Duration::from_secs(60));
+        let available = |snapshot: Option<ClientAccountSnapshot>| {
+            snapshot.expect("client has no account").available
+        };
+        engine
+            .process_one_tx(deposit(1, 1, dec!(1.0)))
+            .expect("failed to process tx");
+        assert_eq!(available(engine.account_snapshot(1).await), dec!(1.0));
+        engine
+            .process_one_tx(Tx {
+                client_id: 1,
+                tx_id: 2,
+                inner: TxInner::Transfer {
+                    to_client_id: 2,
+                    amount: dec!(0.5),
+                },
+            })
+            .expect("failed to process tx");
+        assert_eq!(available(engine.account_snapshot(1).await), dec!(0.5));
+        assert_eq!(available(engine.account_snapshot(2).await), dec!(0.5));
+        engine.reset();
+        assert_eq!(engine.account_snapshot(1).await, None);
+    }
+
+    fn fee(client_id: ClientId, tx_id: TxId, amount: Amount) -> Tx {
+        Tx {
+            client_id,
+            tx_id,
+            inner: TxInner::Fee { amount },
+        }
+    }
+
+    #[test]
+    fn fee_ledger_credits_cannot_be_disputed() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty()).with_transaction_fee_ledger(9);
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        assert_eq!(outcome(deposit(1, 1, dec!(5.0))), TxOutcome::Applied);
+        assert_eq!(outcome(fee(1, 2, dec!(1.0))), TxOutcome::Applied);
+        for tx_id in [2, TxId::MAX] {
+            assert_eq!(
+                outcome(Tx {
+                    client_id: 9,
+                    tx_id,
+                    inner: TxInner::Dispute,
+                }),
+                TxOutcome::Ignored(IgnoreReason::UnknownTx)
+            );
+        }
+
+        let accounts: HashMap<_, _> = engine.accounts().collect();
+        assert_eq!(accounts[&1].available, dec!(4.0));
+        assert_eq!(accounts[&9].available, dec!(1.0));
+        assert_eq!(engine.transaction_count(), 2);
+        assert_eq!(
+            engine.drain_audit_events(),
+            vec![AuditEvent::FeeCredited {
+                tx_id: 2,
+                from_client: 1,
+                fee_account: 9,
+                amount: dec!(1.0),
+            }]
+        );
+    }
+
+    #[test]
+    fn fee_ledger_is_held_to_max_balance() {
+        let mut engine = PaymentsEngineBuilder::new()
+            .max_balance(dec!(10.0))
+            .build(tokio_stream::empty())
+            .with_transaction_fee_ledger(9);
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        assert_eq!(outcome(deposit(9, 1, dec!(9.5))), TxOutcome::Applied);
+        assert_eq!(outcome(deposit(1, 2, dec!(5.0))), TxOutcome::Applied);
+        assert_eq!(
+            outcome(fee(1, 3, dec!(1.0))),
+            TxOutcome::Ignored(IgnoreReason::MaxBalanceExceeded)
+        );
+        assert_eq!(outcome(fee(1, 4, dec!(0.5))), TxOutcome::Applied);
+        let accounts: HashMap<_, _> = engine.accounts().collect();
+        assert_eq!(accounts[&1].available, dec!(4.5));
+        assert_eq!(accounts[&9].available, dec!(10.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved client id")]
+    fn fee_ledger_rejects_reserved_client_id() {
+        let _ = PaymentsEngine::new(tokio_stream::empty()).with_transaction_fee_ledger(0);
+    }
+
+    fn two_way_rates() -> MockExchangeRateProvider {
+        MockExchangeRateProvider::new()
+            .with_rate(Currency::USD, Currency::EUR, dec!(0.9))
+            .with_rate(Currency::EUR, Currency::USD, dec!(1.11115))
+    }
+
+    // Clients 1, 3 and 4 hold USD, client 2 EUR. Client 3's deposit is
+    // disputed, client 2's second deposit is held in EUR, and client 4 is
+    // locked by a chargeback but keeps 7 USD.
+    fn multicurrency_engine() -> PaymentsEngine<Empty<Tx>> {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty())
+            .with_exchange_rate_provider(Arc::new(two_way_rates()));
+        let tx = |client_id, tx_id, inner| Tx {
+            client_id,
+            tx_id,
+            inner,
+        };
+        let outcomes = engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(100.0)),
+            fx_transfer(1, 2, 2, dec!(10.0), dec!(0.9)),
+            deposit(3, 3, dec!(5.0)),
+            tx(3, 3, TxInner::Dispute),
+            deposit(2, 4, dec!(2.0)),
+            tx(2, 4, TxInner::Dispute),
+            deposit(4, 5, dec!(7.0)),
+            deposit(4, 6, dec!(1.0)),
+            tx(4, 6, TxInner::Dispute),
+            tx(4, 6, TxInner::Chargeback),
+        ]);
+        assert!(outcomes.iter().all(Result::is_ok));
+        engine
+    }
+
+    #[test]
+    fn total_balance_in_currency_sums_available_funds() {
+        let engine = multicurrency_engine();
+        // Locked accounts still count
+        assert_eq!(engine.total_balance_in_currency(Currency::USD), dec!(97.0));
+        assert_eq!(engine.total_balance_in_currency(Currency::EUR), dec!(9.0));
+        assert_eq!(engine.total_balance_in_currency(Currency::GBP), dec!(0.0));
+    }
+
+    #[test]
+    fn total_balance_converted_to_applies_rates() {
+        let engine = multicurrency_engine();
+        // 9 EUR is 10.00035 USD, rounded half to even
+        assert_eq!(
+            engine
+                .total_balance_converted_to(Currency::USD)
+                .expect("failed to convert"),
+            dec!(107.0004)
+        );
+        assert_eq!(
+            engine
+                .total_balance_converted_to(Currency::EUR)
+                .expect("failed to convert"),
+            dec!(96.3)
+        );
+        assert!(engine.total_balance_converted_to(Currency::GBP).is_err());
+    }
+
+    #[test]
+    fn balance_report_by_currency_splits_available_and_held() {
+        let engine = multicurrency_engine();
+        let mut expected = HashMap::new();
+        expected.insert(Currency::USD, (dec!(97.0), dec!(5.0)));
+        expected.insert(Currency::EUR, (dec!(9.0), dec!(2.0)));
+        assert_eq!(engine.balance_report_by_currency(), expected);
+        assert!(PaymentsEngine::new(tokio_stream::empty())
+            .balance_report_by_currency()
+            .is_empty());
+    }
+
+    #[test]
+    fn multicurrency_report_adds_a_currency_column() {
+        let mut engine = multicurrency_engine();
+        // Too small to show at four decimal places
+        engine
+            .process_one_tx(deposit(1, 7, dec!(0.00001)))
+            .expect("failed to process tx");
+        assert!(engine.is_multicurrency());
+        let mut report = Vec::new();
+        engine
+            .write_multicurrency_report(&mut report)
+            .expect("failed to write report");
+        assert_eq!(
+            String::from_utf8(report).expect("report is not UTF-8"),
+            "client,currency,available,held,total,locked\n\
+             1,USD,90.0,0.0,90.0,false\n\
+             2,EUR,9.0,2.0,11.0,false\n\
+             3,USD,0.0,5.0,5.0,false\n\
+             4,USD,7.0,0.0,7.0,true\n"
+        );
+    }
+
+    #[test]
+    fn convert_all_to_base_currency_converts_balances_and_disputes() {
+        let mut engine = multicurrency_engine();
+        engine.drain_audit_events();
+        engine
+            .convert_all_to_base_currency(Currency::USD, &two_way_rates())
+            .expect("failed to convert");
+
+        // 9 EUR is 10.00035 USD, rounded half to even
+        assert_eq!(
+            engine.drain_audit_events(),
+            vec![AuditEvent::CurrencyConversion {
+                client_id: 2,
+                from_currency: Currency::EUR,
+                to_currency: Currency::USD,
+                rate: dec!(1.11115),
+                available_before: dec!(9.0),
+                held_before: dec!(2.0),
+                available_after: dec!(10.0004),
+                held_after: dec!(2.2223),
+            }]
+        );
+        assert!(!engine.is_multicurrency());
+        // Resolving releases the converted amount of the disputed deposit
+        engine
+            .process_one_tx(Tx {
+                client_id: 2,
+                tx_id: 4,
+                inner: TxInner::Resolve,
+            })
+            .expect("failed to process tx");
+        let accounts: HashMap<_, _> = engine.accounts().collect();
+        assert_eq!(accounts[&2].available, dec!(12.2227));
+        assert_eq!(accounts[&2].held, dec!(0.0));
+        assert_eq!(accounts[&2].currency(), Currency::USD);
+        assert_eq!(accounts[&1].available, dec!(90.0));
+    }
+
+    #[test]
+    fn convert_all_to_base_currency_includes_locked_accounts() {
+        let mut engine = multicurrency_engine();
+        engine
+            .convert_all_to_base_currency(Currency::EUR, &two_way_rates())
+            .expect("failed to convert");
+        let accounts: HashMap<_, _> = engine.accounts().collect();
+        assert_eq!(accounts[&4].available, dec!(6.3));
+        assert!(accounts[&4].locked);
+        assert_eq!(accounts[&3].held, dec!(4.5));
+        assert_eq!(engine.total_held(), dec!(6.5));
+    }
+
+    #[test]
+    fn convert_all_to_base_currency_fails_without_touching_accounts() {
+        let mut engine = multicurrency_engine();
+        engine.drain_audit_events();
+        let before: Vec<_> = engine.accounts().collect();
+        assert!(engine
+            .convert_all_to_base_currency(Currency::GBP, &two_way_rates())
+            .is_err());
+        assert_eq!(engine.accounts().collect::<Vec<_>>(), before);
+        assert!(engine.drain_audit_events().is_empty());
+    }
+
+    #[test]
+    fn monthly_fee_pass_skips_locked_and_short_accounts() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        let tx = |client_id, tx_id, inner| Tx {
+            client_id,
+            tx_id,
+            inner,
+        };
+        engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(10.0)),
+            deposit(2, 2, dec!(0.5)),
+            deposit(3, 3, dec!(5.0)),
+            deposit(3, 4, dec!(1.0)),
+            tx(3, 4, TxInner::Dispute),
+            tx(3, 4, TxInner::Chargeback),
+        ]);
+
+        assert_eq!(
+            engine.monthly_fee_pass(dec!(0.0), Utc::now()),
+            FeePassSummary::default()
+        );
+        assert_eq!(
+            engine.monthly_fee_pass(dec!(1.0), Utc::now()),
+            FeePassSummary {
+                charged: 1,
+                skipped: 2,
+            }
+        );
+        let accounts: HashMap<_, _> = engine.accounts().collect();
+        assert_eq!(accounts[&1].available, dec!(9.0));
+        assert_eq!(accounts[&2].available, dec!(0.5));
+        assert_eq!(accounts[&3].available, dec!(5.0));
+        assert_eq!(engine.total_available(), dec!(14.5));
+        assert_eq!(engine.processing_summary().transactions_accepted, 7);
+
+        // The fee is recorded under an internal id but cannot be disputed
+        assert_eq!(
+            engine
+                .process_one_tx(tx(1, TxId::MAX, TxInner::Dispute))
+                .expect("failed to process tx"),
+            TxOutcome::Ignored(IgnoreReason::UnknownTx)
+        );
+        assert_eq!(
+            engine.rollback_tx(TxId::MAX),
+            Err(EngineError::TxNotReversible(TxId::MAX))
+        );
+        let accounts: HashMap<_, _> = engine.accounts().collect();
+        assert_eq!(accounts[&1].available, dec!(9.0));
+        assert_eq!(accounts[&1].held, dec!(0.0));
+    }
+
+    #[test]
+    fn simulate_chargeback_impact_projects_without_applying() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        let tx = |client_id, tx_id, inner| Tx {
+            client_id,
+            tx_id,
+            inner,
+        };
+        engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(10.0)),
+            deposit(1, 2, dec!(4.0)),
+            deposit(2, 3, dec!(5.0)),
+            tx(1, 2, TxInner::Dispute),
+        ]);
+
+        assert_eq!(
+            engine.simulate_chargeback_impact(1, 2),
+            SimulationResult {
+                available: dec!(10.0),
+                held: dec!(0.0),
+                locked: true,
+                rejection: None,
+            }
+        );
+        let row = &engine.report_rows()[0];
+        assert_eq!((row.held, row.locked), (dec!(4.0), false));
+
+        let rejection = |client_id, tx_id| {
+            engine
+                .simulate_chargeback_impact(client_id, tx_id)
+                .rejection
+        };
+        assert_eq!(rejection(9, 2), Some(ChargebackRejection::AccountNotFound));
+        assert_eq!(rejection(1, 99), Some(ChargebackRejection::TxNotFound));
+        assert_eq!(rejection(2, 2), Some(ChargebackRejection::WrongClient));
+        assert_eq!(rejection(1, 1), Some(ChargebackRejection::NotDisputed));
+
+        engine
+            .process_one_tx(tx(1, 2, TxInner::Chargeback))
+            .expect("failed to process tx");
+        assert_eq!(
+            engine.simulate_chargeback_impact(1, 1),
+            SimulationResult {
+                available: dec!(10.0),
+                held: dec!(0.0),
+                locked: true,
+                rejection: Some(ChargebackRejection::AccountLocked),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn process_txs_with_callback_sees_every_outcome() {
+        let txs = vec![deposit(1, 1, dec!(1.0)), withdrawal(1, 2, dec!(2.0))];
+        let mut engine = PaymentsEngine::new(tokio_stream::iter(txs));
+        let seen = Mutex::new(Vec::new());
+        engine
+            .process_txs_with_callback(|tx, outcome| seen.lock().push((tx.tx_id, *outcome)))
+            .await
+            .expect("failed to process txs");
+        assert_eq!(
+            seen.into_inner(),
+            vec![
+                (1, TxOutcome::Applied),
+                (2, TxOutcome::Ignored(IgnoreReason::InsufficientFunds))
+            ]
+        );
+    }
+
+    fn scanned_totals(engine: &PaymentsEngine<Empty<Tx>>) -> (Amount, Amount) {
+        engine
+            .accounts()
+            .fold((dec!(0), dec!(0)), |(available, held), (_, account)| {
+                (available + account.available(), held + account.held())
+            })
+    }
+
+    #[test]
+    fn running_totals_match_a_full_scan() {
+        let tx = |client_id, tx_id, inner| Tx {
+            client_id,
+            tx_id,
+            inner,
+        };
+        let mut engine = PaymentsEngine::new(tokio_stream::empty()).with_transaction_fee_ledger(9);
+        engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(10.0)),
+            deposit(2, 2, dec!(5.0)),
+            withdrawal(1, 3, dec!(2.5)),
+            tx(1, 1, TxInner::Dispute),
+            tx(2, 2, TxInner::Dispute),
+            tx(2, 2, TxInner::Resolve),
+            tx(1, 1, TxInner::Chargeback),
+            tx(
+                2,
+                4,
+                TxInner::Transfer {
+                    to_client_id: 3,
+                    amount: dec!(1.5),
+                },
+            ),
+            tx(2, 5, TxInner::Fee { amount: dec!(0.5) }),
+            withdrawal(2, 6, dec!(1.0)),
+        ]);
+        let check = |engine: &PaymentsEngine<Empty<Tx>>| {
+            let stats = engine.statistics();
+            assert_eq!(
+                (stats.total_available, stats.total_held),
+                scanned_totals(engine)
+            );
+            let (available, held) = scanned_totals(engine);
+            assert_eq!(engine.total_assets(), available + held);
+        };
+        check(&engine);
+        engine.rollback_tx(6).expect("failed to roll back");
+        engine.apply_interest_compound(100, 2, Utc::now());
+        check(&engine);
+        engine.reset_account(2);
+        check(&engine);
+        let state = engine.save_state();
+        engine.reset();
+        check(&engine);
+        engine.load_state(state);
+        check(&engine);
+    }
+
+    #[cfg(feature = "bench")]
+    #[test]
+    fn update_timings_cover_every_update() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        assert_eq!(engine.processing_summary().update_timings, None);
+        for tx_id in 1..=200 {
+            engine.update(deposit(1, tx_id, dec!(1.0))).unwrap();
+        }
+        let timings = engine
+            .processing_summary()
+            .update_timings
+            .expect("no timings recorded");
+        assert!(timings.min <= timings.mean);
+        assert!(timings.mean <= timings.max);
+        assert!(timings.p99 <= timings.max);
+        engine.reset();
+        assert_eq!(engine.processing_summary().update_timings, None);
+    }
+
+    #[cfg(feature = "bench")]
+    #[test]
+    fn update_histogram_p99_is_within_an_eighth() {
+        for bucket in 0..UpdateHistogram::BUCKETS {
+            let end = UpdateHistogram::bucket_end(bucket);
+            assert_eq!(UpdateHistogram::bucket(end), bucket);
+            if bucket + 1 < UpdateHistogram::BUCKETS {
+                assert_eq!(UpdateHistogram::bucket(end + 1), bucket + 1);
+            }
+        }
+
+        let mut histogram = UpdateHistogram::default();
+        for micros in 1..=1000 {
+            histogram.record(std::time::Duration::from_micros(micros));
+        }
+        let mut merged = UpdateHistogram::default();
+        merged.merge(&histogram);
+        merged.merge(&UpdateHistogram::default());
+        let timings = UpdateTimings::from_histogram(&merged).expect("no timings recorded");
+        assert_eq!(timings.min, std::time::Duration::from_micros(1));
+        assert_eq!(timings.max, std::time::Duration::from_micros(1000));
+        assert_eq!(timings.mean, std::time::Duration::from_nanos(500_500));
+        let p99 = std::time::Duration::from_micros(990);
+        assert!(timings.p99 >= p99);
+        assert!(timings.p99 <= p99 + p99 / 8);
+    }
+
+    #[test]
+    fn rollback_tx_refuses_charged_back_txs_and_locked_accounts() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        let dispute = Tx {
+            client_id: 1,
+            tx_id: 2,
+            inner: TxInner::Dispute,
+        };
+        let chargeback = Tx {
+            inner: TxInner::Chargeback,
+            ..dispute.clone()
+        };
+        engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(5.0)),
+            deposit(1, 2, dec!(3.0)),
+            dispute,
+            chargeback,
+        ]);
+
+        assert_eq!(engine.rollback_tx(2), Err(EngineError::TxChargedBack(2)));
+        assert_eq!(engine.rollback_tx(1), Err(EngineError::AccountLocked(1)));
+        let row = &engine.report_rows()[0];
+        assert_eq!(row.available, dec!(5.0));
+        assert_eq!(row.total, dec!(5.0));
+        assert_eq!(engine.transaction_count(), 2);
+    }
+
+    #[test]
+    fn rollback_tx_forgets_the_tx_in_statistics_and_velocity() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let mut engine = PaymentsEngine::new(tokio_stream::empty())
+            .with_clock(clock)
+            .with_velocity_check(dec!(10.0));
+        let mut outcome = |tx| engine.process_one_tx(tx).expect("failed to process tx");
+        assert_eq!(outcome(deposit(1, 1, dec!(6.0))), TxOutcome::Applied);
+        assert_eq!(outcome(deposit(1, 2, dec!(3.0))), TxOutcome::Applied);
+
+        assert_eq!(engine.rollback_tx(2), Ok(()));
+        let stats = engine.statistics();
+        assert_eq!(stats.deposit_count, 1);
+        assert_eq!(engine.processing_summary().transactions_accepted, 1);
+        let context = engine.account_context(1);
+        assert_eq!(context.tx_count, 1);
+        assert_eq!(context.avg_deposit, dec!(6.0));
+        // Only the remaining deposit counts toward the hourly limit
+        assert_eq!(
+            engine
+                .process_one_tx(deposit(1, 3, dec!(4.0)))
+                .expect("failed to process tx"),
+            TxOutcome::Applied
+        );
+    }
+
+    #[tokio::test]
+    async fn parallel_processing_matches_sequential() {
+        let tx = |client_id, tx_id, inner| Tx {
+            client_id,
+            tx_id,
+            inner,
+        };
+        let mut txs: Vec<_> = (1..=40)
+            .map(|tx_id| deposit((tx_id % 8) as ClientId, tx_id, dec!(10.0)))
+            .collect();
+        txs.extend(vec![
+            withdrawal(1, 41, dec!(25.0)),
+            tx(2, 2, TxInner::Dispute),
+            tx(3, 3, TxInner::Dispute),
+            tx(3, 3, TxInner::Chargeback),
+            tx(
+                4,
+                42,
+                TxInner::Transfer {
+                    to_client_id: 5,
+                    amount: dec!(30.0),
+                },
+            ),
+            // Only covered once the transfer from another shard has landed
+            withdrawal(5, 43, dec!(75.0)),
+            tx(
+                6,
+                44,
+                TxInner::Transfer {
+                    to_client_id: 9,
+                    amount: dec!(5.0),
+                },
+            ),
+            withdrawal(9, 45, dec!(5.0)),
+            tx(
+                3,
+                46,
+                TxInner::Transfer {
+                    to_client_id: 1,
+                    amount: dec!(1.0),
+                },
+            ),
+        ]);
+
+        let mut sequential = PaymentsEngine::new(tokio_stream::empty());
+        sequential.process_txs_batch(txs.clone());
+        let mut parallel = PaymentsEngine::new(tokio_stream::iter(txs));
+        parallel
+            .process_txs_parallel(4)
+            .await
+            .expect("failed to process in parallel");
+
+        assert_eq!(
+            parallel.accounts().collect::<Vec<_>>(),
+            sequential.accounts().collect::<Vec<_>>()
+        );
+        assert_eq!(parallel.total_assets(), sequential.total_assets());
+        let untimed = |stats: EngineStatistics| EngineStatistics {
+            uptime: Default::default(),
+            txs_per_second: 0.0,
+            ..stats
+        };
+        assert_eq!(
+            untimed(parallel.statistics()),
+            untimed(sequential.statistics())
+        );
+        let (parallel, sequential) = (
+            parallel.processing_summary(),
+            sequential.processing_summary(),
+        );
+        assert_eq!(
+            parallel.transactions_accepted,
+            sequential.transactions_accepted
+        );
+        assert_eq!(
+            parallel.transactions_ignored,
+            sequential.transactions_ignored
+        );
+    }
+
+    #[test]
+    fn merge_takes_over_disjoint_engines_only() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        engine.process_txs_batch(vec![deposit(1, 1, dec!(1.0))]);
+        let mut other = PaymentsEngine::new(tokio_stream::empty());
+        other.process_txs_batch(vec![
+            deposit(2, 2, dec!(2.0)),
+            Tx {
+                client_id: 2,
+                tx_id: 2,
+                inner: TxInner::Dispute,
+            },
+        ]);
+        engine.merge(other).expect("failed to merge");
+        assert_eq!(engine.total_assets(), dec!(3.0));
+        assert_eq!(engine.disputed_amount_for_client(2), dec!(2.0));
+        assert_eq!(engine.processing_summary().transactions_accepted, 3);
+
+        let mut same_client = PaymentsEngine::new(tokio_stream::empty());
+        same_client.process_txs_batch(vec![deposit(1, 3, dec!(1.0))]);
+        assert!(engine.merge(same_client).is_err());
+        let mut same_tx_id = PaymentsEngine::new(tokio_stream::empty());
+        same_tx_id.process_txs_batch(vec![deposit(3, 1, dec!(1.0))]);
+        assert!(engine.merge(same_tx_id).is_err());
+        assert_eq!(engine.total_assets(), dec!(3.0));
+    }
+
+    struct PanickingScorer(TxId);
+
+    impl FraudScorer for PanickingScorer {
+        fn score(&self, tx: &Tx, _context: &AccountContext) -> FraudScore {
+            if tx.tx_id == self.0 {
+                panic!("scorer failed on tx {}", tx.tx_id);
+            }
+            0.0
+        }
+    }
+
+    #[tokio::test]
+    async fn panic_recovery_skips_the_panicking_tx() {
+        let txs = vec![
+            deposit(1, 1, dec!(1.0)),
+            deposit(1, 2, dec!(2.0)),
+            deposit(1, 3, dec!(4.0)),
+        ];
+        let mut engine = PaymentsEngine::new(tokio_stream::iter(txs))
+            .with_fraud_score(Arc::new(PanickingScorer(2)), 0.5);
+        engine
+            .process_txs_with_panic_recovery(1)
+            .await
+            .expect("failed to process input");
+        assert_eq!(engine.panic_count(), 1);
+        assert_eq!(engine.total_assets(), dec!(5.0));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "scorer failed on tx 2")]
+    async fn panic_recovery_gives_up_past_max_panics() {
+        let txs = vec![deposit(1, 1, dec!(1.0)), deposit(1, 2, dec!(2.0))];
+        let mut engine = PaymentsEngine::new(tokio_stream::iter(txs))
+            .with_fraud_score(Arc::new(PanickingScorer(2)), 0.5);
+        let _ = engine.process_txs_with_panic_recovery(0).await;
+    }
+
+    #[test]
+    fn totals_add_up_to_net_deposits() {
+        let tx = |client_id, tx_id, inner| Tx {
+            client_id,
+            tx_id,
+            inner,
+        };
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(10.0)),
+            deposit(2, 2, dec!(7.5)),
+            deposit(2, 3, dec!(2.5)),
+            withdrawal(1, 4, dec!(4.0)),
+            tx(2, 2, TxInner::Dispute),
+            // Before the chargeback locks the account
+            tx(2, 3, TxInner::Dispute),
+            tx(2, 2, TxInner::Chargeback),
+        ]);
+        // 20.0 deposited, 4.0 withdrawn and 7.5 charged back
+        assert_eq!(engine.total_available(), dec!(6.0));
+        assert_eq!(engine.total_held(), dec!(2.5));
+        assert_eq!(engine.total_assets(), dec!(8.5));
+    }
+
+    #[test]
+    fn tx_ids_past_u32_max_are_distinct() {
+        let first = u64::from(u32::MAX);
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        let results = engine.process_txs_batch(vec![
+            deposit(1, first, dec!(1.0)),
+            // Would wrap around to 0 as a u32
+            deposit(1, first + 1, dec!(2.0)),
+            deposit(1, 0, dec!(4.0)),
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(engine.total_assets(), dec!(7.0));
+    }
+
+    #[test]
+    fn statistics_count_txs_by_type_and_reason() {
+        let tx = |client_id, tx_id, inner| Tx {
+            client_id,
+            tx_id,
+            inner,
+        };
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(10.0)),
+            deposit(1, 2, dec!(5.0)),
+            withdrawal(1, 3, dec!(1.0)),
+            withdrawal(1, 4, dec!(100.0)),
+            tx(1, 1, TxInner::Dispute),
+            tx(1, 1, TxInner::Resolve),
+            tx(1, 2, TxInner::Dispute),
+            tx(1, 2, TxInner::Chargeback),
+            deposit(1, 5, dec!(1.0)),
+        ]);
+        let stats = engine.statistics();
+        assert_eq!(
+            (
+                stats.deposit_count,
+                stats.withdrawal_count,
+                stats.dispute_count,
+                stats.resolve_count,
+                stats.chargeback_count,
+            ),
+            (2, 1, 2, 1, 1)
+        );
+        assert_eq!(stats.total_locked_accounts, 1);
+        assert_eq!(
+            stats.ignored_by_reason,
+            vec![
+                (IgnoreReason::InsufficientFunds, 1),
+                (IgnoreReason::AccountLocked, 1),
+            ]
+            .into_iter()
+            .collect()
+        );
+        assert!(stats.txs_per_second.is_finite());
+    }
+
+    #[test]
+    fn client_id_zero_is_rejected() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        let results = engine.process_txs_batch(vec![
+            deposit(0, 1, dec!(1.0)),
+            deposit(1, 2, dec!(5.0)),
+            Tx {
+                client_id: 1,
+                tx_id: 3,
+                inner: TxInner::Transfer {
+                    to_client_id: 0,
+                    amount: dec!(1.0),
+                },
+            },
+        ]);
+        assert_eq!(
+            results,
+            vec![
+                Err(EngineError::Ignored {
+                    tx_id: 1,
+                    reason: IgnoreReason::InvalidClientId
+                }),
+                Ok(()),
+                Err(EngineError::Ignored {
+                    tx_id: 3,
+                    reason: IgnoreReason::InvalidRecipient
+                }),
+            ]
+        );
+        assert!(!engine.client_accounts.contains_key(&0));
+        assert!(engine
+            .warm_up(vec![deposit(0, 4, dec!(1.0))].into_iter())
+            .is_err());
+        assert!(!engine.client_accounts.contains_key(&0));
+    }
+
+    #[test]
+    fn debug_shows_counts() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(1.0)),
+            deposit(2, 2, dec!(2.0)),
+            Tx {
+                client_id: 2,
+                tx_id: 2,
+                inner: TxInner::Dispute,
+            },
+        ]);
+        assert_eq!(
+            format!("{:?}", engine),
+            "PaymentsEngine { clients: 2, txs: 2, disputed: 1 }"
+        );
+    }
+
+    #[test]
+    fn disputed_amount_matches_held_funds() {
+        let tx = |client_id, tx_id, inner| Tx {
+            client_id,
+            tx_id,
+            inner,
+        };
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        engine.process_txs_batch(vec![
+            deposit(1, 1, dec!(10.0)),
+            deposit(1, 2, dec!(4.0)),
+            withdrawal(1, 3, dec!(1.5)),
+            deposit(2, 4, dec!(3.0)),
+            tx(1, 1, TxInner::Dispute),
+            tx(1, 3, TxInner::Dispute),
+            tx(2, 4, TxInner::Dispute),
+            tx(2, 4, TxInner::Resolve),
+        ]);
+        for (id, account) in engine.accounts() {
+            assert_eq!(engine.disputed_amount_for_client(id), account.held());
+        }
+        assert_eq!(engine.disputed_amount_for_client(1), dec!(11.5));
+        assert_eq!(engine.disputed_amount_for_client(2), dec!(0.0));
+        assert_eq!(engine.disputed_amount_for_client(3), dec!(0.0));
+    }
+
+    #[test]
+    fn report_amounts_do_not_depend_on_input_scale() {
+        assert_eq!(report_amount(dec!(2)).to_string(), "2.0");
+        assert_eq!(report_amount(dec!(2.000)).to_string(), "2.0");
+        assert_eq!(report_amount(dec!(1.50000)).to_string(), "1.5");
+        assert_eq!(report_amount(dec!(1.23456)).to_string(), "1.2346");
+        assert_eq!(report_amount(-dec!(0.0)).to_string(), "0.0");
+    }
+
+    #[tokio::test]
+    async fn accounts_as_csv_matches_report() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        engine
+            .process_txs_from_csv_str("type,client,tx,amount\ndeposit,2,1,2.5\ndeposit,1,2,1.0\n")
+            .await
+            .expect("failed to process input");
+        assert_eq!(
+            engine.accounts_as_csv(),
+            "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n2,2.5,0.0,2.5,false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn builder_reads_headerless_csv() {
+        let mut engine = PaymentsEngineBuilder::new()
+            .has_header(false)
+            .build(tokio_stream::empty());
+        engine
+            .process_txs_from_csv_str("deposit,1,1,2.0\nwithdrawal,1,2,0.5\n")
+            .await
+            .expect("failed to process input");
+        assert_eq!(engine.report_rows()[0].available, dec!(1.5));
+    }
+
+    #[tokio::test]
+    async fn process_txs_from_csv_str_returns_summary() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        let summary = engine
+            .process_txs_from_csv_str(
+                "type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,5.0\n",
+            )
+            .await
+            .expect("failed to process input");
+        assert_eq!(summary.transactions_accepted, 1);
+        assert_eq!(summary.transactions_ignored, 1);
+        assert_eq!(summary.clients, 1);
+    }
+
+    #[test]
+    fn reset_clears_all_state() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        engine.process_txs_batch(vec![deposit(1, 1, dec!(2.0)), withdrawal(1, 2, dec!(5.0))]);
+        engine.reset();
+        assert_eq!(engine.client_count(), 0);
+        assert_eq!(engine.transaction_count(), 0);
+        assert_eq!(engine.processing_summary(), ProcessingSummary::default());
+        let results = engine.process_txs_batch(vec![deposit(1, 1, dec!(3.0))]);
+        assert_eq!(results, vec![Ok(())]);
+        assert_eq!(engine.report_rows()[0].available, dec!(3.0));
+    }
+
+    #[test]
+    fn tx_ids_can_be_reused_after_reset_account() {
+        let mut engine = PaymentsEngine::new(tokio_stream::empty());
+        engine.update(deposit(1, 42, dec!(5.0))).unwrap();
+        assert!(engine.reset_account(1));
+        engine.update(deposit(2, 42, dec!(3.0))).unwrap();
+
+        assert!(engine.ignored_counts().is_empty());
+        assert_eq!(engine.transaction_count(), 1);
+        let rows = engine.report_rows();
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].client_id, rows[0].available), (2, dec!(3.0)));
+    }
+
+    #[tokio::test]
+    async fn health_check_warns_on_high_error_rate() {
+        let txs = vec![deposit(1, 1, dec!(1.0)), withdrawal(1, 2, dec!(5.0))];
+        let mut engine = PaymentsEngine::new(tokio_stream::iter(txs));
+        engine.process_txs().await.expect("failed to process txs");
+        let health = engine.health_check();
+        assert!(health.ok);
+        let status_of = |name| {
+            health
+                .checks
+                .iter()
+                .find(|check| check.name == name)
+                .map(|check| check.status)
+        };
+        assert_eq!(status_of("error_rate"), Some(CheckStatus::Warn));
+        assert_eq!(status_of("locked_account_ratio"), Some(CheckStatus::Ok));
+    }
+
+    #[tokio::test]
+    async fn accounts_are_yielded_in_client_order() {
+        let txs = vec![deposit(2, 1, dec!(2.0)), deposit(1, 2, dec!(1.0))];
+        let mut engine = PaymentsEngine::new(tokio_stream::iter(txs));
+        engine.process_txs().await.expect("failed to process txs");
+        let accounts: Vec<_> = engine
+            .accounts()
+            .map(|(id, account)| (id, account.available()))
+            .collect();
+        assert_eq!(accounts, vec![(1, dec!(1.0)), (2, dec!(2.0))]);
+    }
+
+    #[tokio::test]
+    async fn load_state_restores_saved_engine() {
+        let txs = vec![
+            deposit(1, 1, dec!(5.0)),
+            deposit(2, 2, dec!(3.0)),
+            Tx {
+                client_id: 1,
+                tx_id: 1,
+                inner: TxInner::Dispute,
+            },
+        ];
+        let mut engine = PaymentsEngine::new(tokio_stream::iter(txs));
+        engine.process_txs().await.expect("failed to process txs");
+        let json = serde_json::to_string(&engine.save_state()).expect("failed to serialize");
+        let state: EngineState = serde_json::from_str(&json).expect("failed to deserialize");
+        assert_eq!(state, engine.save_state());
+
+        let resolve = Tx {
+            client_id: 1,
+            tx_id: 1,
+            inner: TxInner::Resolve,
+        };
+        let mut restored = PaymentsEngine::new(tokio_stream::iter(vec![resolve]));
+        restored.load_state(state);
+        assert_eq!(restored.report_rows(), engine.report_rows());
+        restored.process_txs().await.expect("failed to process txs");
+        let rows = restored.report_rows();
+        assert_eq!((rows[0].available, rows[0].held), (dec!(5.0), dec!(0.0)));
+    }
+
+    #[test]
+    fn display_client_account() {
+        let mut account = ClientAccount::new();
+        account.deposit(dec!(2.5));
+        account.dispute(dec!(1));
+        assert_eq!(account.to_string(), "1.5000,1.0000,2.5000,false");
+    }
+
+    #[test]
+    fn display_tx() {
+        assert_eq!(
+            deposit(7, 42, dec!(100)).to_string(),
+            "tx#42 (client 7): deposit of 100.0000"
+        );
+        let dispute = Tx {
+            client_id: 7,
+            tx_id: 42,
+            inner: TxInner::Dispute,
+        };
+        assert_eq!(dispute.to_string(), "tx#42 (client 7): dispute");
+        assert_eq!(
+            TxInner::Transfer {
+                to_client_id: 2,
+                amount: dec!(1.5)
+            }
+            .to_string(),
+            "transfer of 1.5000 to client 2"
+        );
+    }
+
+    fn engine_tx(clients: Range<ClientId>, tx_ids: Range<TxId>) -> impl Strategy<Value = Tx> {
+        (clients, tx_ids, 0..5u8, amount()).prop_map(|(client_id, tx_id, kind, amount)| Tx {
+            client_id,
+            tx_id,
+            inner: match kind {
+                0 => TxInner::Deposit { amount },
+                1 => TxInner::Withdrawal { amount },
+                2 => TxInner::Dispute,
+                3 => TxInner::Resolve,
+                _ => TxInner::Chargeback,
+            },
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn merge_is_commutative_for_disjoint_engines(
+            a in proptest::collection::vec(engine_tx(1..5, 1..20), 0..32),
+            b in proptest::collection::vec(engine_tx(5..10, 20..40), 0..32),
+        ) {
+            let engine = |txs: &Vec<Tx>| {
+                let mut engine = PaymentsEngine::new(tokio_stream::empty());
+                engine.process_txs_batch(txs.clone());
+                engine
+            };
+            let mut ab = engine(&a);
+            ab.merge(engine(&b)).expect("failed to merge b into a");
+            let mut ba = engine(&b);
+            ba.merge(engine(&a)).expect("failed to merge a into b");
+            prop_assert_eq!(ab.report_rows(), ba.report_rows());
+        }
+
+        #[test]
+        fn client_account_invariants(ops in proptest::collection::vec(account_op(), 0..64)) {
+            let mut account = ClientAccount::new();
+            let mut deposits: Vec<(Amount, DepositState)> = Vec::new();
+            let mut expected_total = dec!(0);
+            let mut charged_back = false;
+
+            for op in ops {
+                // The engine ignores every transaction on a locked account
+                if account.locked {
+                    break;
+                }
+                match op {
+                    AccountOp::Deposit(amount) => {
+                        account.deposit(amount);
+                        deposits.push((amount, DepositState::Settled));
+                        expected_total += amount;
+                    }
+                    AccountOp::Withdrawal(amount) => {
+                        if account.available >= amount {
+                            account.withdrawal(amount);
+                            expected_total -= amount;
+                        }
+                    }
+                    AccountOp::Dispute(i) => {
+                        // The engine refuses disputes for more than is available
+                        match pick(&deposits, DepositState::Settled, i) {
+                            Some(j) if account.available >= deposits[j].0 => {
+                                account.dispute(deposits[j].0);
+                                deposits[j].1 = DepositState::Disputed;
+                            }
+                            _ => {}
+                        }
+                    }
+                    AccountOp::Resolve(i) => {
+                        if let Some(j) = pick(&deposits, DepositState::Disputed, i) {
+                            account.resolve(deposits[j].0);
+                            deposits[j].1 = DepositState::Settled;
+                        }
+                    }
+                    AccountOp::Chargeback(i) => {
+                        if let Some(j) = pick(&deposits, DepositState::Disputed, i) {
+                            let (amount, _) = deposits.remove(j);
+                            account.chargeback(amount);
+                            expected_total -= amount;
+                            charged_back = true;
+                        }
+                    }
+                }
+
+                let expected_held: Amount = deposits
+                    .iter()
+                    .filter(|(_, state)| *state == DepositState::Disputed)
+                    .map(|(amount, _)| *amount)
+                    .sum();
+                prop_assert_eq!(account.total(), expected_total);
+                prop_assert_eq!(account.held, expected_held);
+                prop_assert!(account.is_solvent());
+                prop_assert_eq!(account.locked, charged_back);
+            }
+        }
+    }
+}
+
+// The shards of process_txs_parallel share done_txs, disputed_txs and
+// charged_back_txs. loom can't see into parking_lot, so this models each
+// path's locking with loom's primitives, and needs updating along with
+// them. Run with `RUSTFLAGS="--cfg loom" cargo test --release --lib loom`.
+#[cfg(loom)]
+mod loom_tests {
+    use std::collections::HashMap;
+    use std::collections::HashSet;
+
+    use loom::sync::Arc;
+    use loom::sync::Mutex;
+    use loom::thread;
+
+    use super::ClientId;
+    use super::TxId;
+
+    #[derive(Clone, Default)]
+    struct History {
+        done_txs: Arc<Mutex<HashMap<TxId, ClientId>>>,
+        disputed_txs: Arc<Mutex<HashSet<TxId>>>,
+        charged_back_txs: Arc<Mutex<HashSet<TxId>>>,
+    }
+
+    impl History {
+        // update_tx_history
+        fn record(&self, client_id: ClientId, tx_id: TxId) {
+            self.done_txs.lock().unwrap().insert(tx_id, client_id);
+        }
+
+        // check_referenced_tx, then dispute
+        fn dispute(&self, client_id: ClientId, tx_id: TxId) -> bool {
+            let referenced = self.done_txs.lock().unwrap().get(&tx_id) == Some(&client_id);
+            if !referenced || self.disputed_txs.lock().unwrap().contains(&tx_id) {
+                return false;
+            }
+            self.disputed_txs.lock().unwrap().insert(tx_id)
+        }
+
+        // check_referenced_tx, then chargeback
+        fn chargeback(&self, client_id: ClientId, tx_id: TxId) -> bool {
+            let referenced = self.done_txs.lock().unwrap().get(&tx_id) == Some(&client_id);
+            if !referenced || !self.disputed_txs.lock().unwrap().remove(&tx_id) {
+                return false;
+            }
+            self.charged_back_txs.lock().unwrap().insert(tx_id)
+        }
+
+        // disputed_amount_for_client and currency conversion
+        fn disputed_for_client(&self, client_id: ClientId) -> usize {
+            let done_txs = self.done_txs.lock().unwrap();
+            let disputed_txs = self.disputed_txs.lock().unwrap();
+            disputed_txs
+                .iter()
+                .filter(|tx_id| done_txs.get(tx_id) == Some(&client_id))
+                .count()
+        }
+
+        // reset_account
+        fn forget_client(&self, client_id: ClientId) {
+            let mut done_txs = self.done_txs.lock().unwrap();
+            let mut disputed_txs = self.disputed_txs.lock().unwrap();
+            let mut charged_back_txs = self.charged_back_txs.lock().unwrap();
+            done_txs.retain(|tx_id, tx_client_id| {
+                let keep = *tx_client_id != client_id;
+                if !keep {
+                    disputed_txs.remove(tx_id);
+                    charged_back_txs.remove(tx_id);
+                }
+                keep
+            });
         }
     }
+
+    #[test]
+    fn shards_sharing_a_history_neither_deadlock_nor_lose_updates() {
+        loom::model(|| {
+            let history = History::default();
+            let shard = {
+                let history = history.clone();
+                thread::spawn(move || {
+                    history.record(1, 1);
+                    assert!(history.dispute(1, 1));
+                    assert!(history.chargeback(1, 1));
+                    history.disputed_for_client(2)
+                })
+            };
+            history.record(2, 2);
+            assert!(history.dispute(2, 2));
+            assert_eq!(history.disputed_for_client(2), 1);
+            history.forget_client(3);
+            assert!(shard.join().unwrap() <= 1);
+
+            assert_eq!(history.done_txs.lock().unwrap().len(), 2);
+            assert_eq!(*history.disputed_txs.lock().unwrap(), HashSet::from([2]));
+            assert_eq!(
+                *history.charged_back_txs.lock().unwrap(),
+                HashSet::from([1])
+            );
+        });
+    }
 }
@@ -0,0 +1,126 @@
+use std::fmt;
+
+use super::engine::Amount;
+use super::engine::ClientId;
+use super::engine::IgnoreReason;
+use super::engine::ProcessingSummary;
+use super::engine::TxId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineError {
+    TxNotFound(TxId),
+    /// The history already held a tx that passed the duplicate check, so
+    /// the engine's own records are inconsistent
+    TxAlreadyRecorded(TxId),
+    TxInDispute(TxId),
+    TxChargedBack(TxId),
+    AccountLocked(ClientId),
+    /// Only deposits and withdrawals can be undone
+    TxNotReversible(TxId),
+    /// Undoing the transaction would take the account's available funds
+    /// below zero
+    InsufficientFunds(TxId),
+    MaxBalanceExceeded {
+        client_id: ClientId,
+        tx_id: TxId,
+        max_balance: Amount,
+    },
+    BelowMinimumDeposit {
+        tx_id: TxId,
+        min_deposit: Amount,
+    },
+    BelowMinimumWithdrawal {
+        tx_id: TxId,
+        min_withdrawal: Amount,
+    },
+    /// The engine declined the transaction for one of the reasons it
+    /// counts in `ignored_counts`
+    Ignored {
+        tx_id: TxId,
+        reason: IgnoreReason,
+    },
+    /// Queued transactions were still being applied when shutdown timed
+    /// out; the summary covers those applied so far
+    ShutdownTimeout(ProcessingSummary),
+}
+
+impl EngineError {
+    /// The reason counted in `ignored_counts` for a transaction the engine
+    /// turned away, or None if this error is not about one.
+    pub fn ignore_reason(&self) -> Option<IgnoreReason> {
+        match self {
+            EngineError::Ignored { reason, .. } => Some(*reason),
+            EngineError::MaxBalanceExceeded { .. } => Some(IgnoreReason::MaxBalanceExceeded),
+            EngineError::BelowMinimumDeposit { .. } => Some(IgnoreReason::BelowMinimumDeposit),
+            EngineError::BelowMinimumWithdrawal { .. } => {
+                Some(IgnoreReason::BelowMinimumWithdrawal)
+            }
+            EngineError::TxNotFound(_)
+            | EngineError::TxAlreadyRecorded(_)
+            | EngineError::TxInDispute(_)
+            | EngineError::TxChargedBack(_)
+            | EngineError::AccountLocked(_)
+            | EngineError::TxNotReversible(_)
+            | EngineError::InsufficientFunds(_)
+            | EngineError::ShutdownTimeout(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::TxNotFound(tx_id) => write!(f, "transaction {} not found", tx_id),
+            EngineError::TxAlreadyRecorded(tx_id) => {
+                write!(f, "transaction {} is already recorded", tx_id)
+            }
+            EngineError::TxInDispute(tx_id) => write!(f, "transaction {} is in dispute", tx_id),
+            EngineError::TxChargedBack(tx_id) => {
+                write!(f, "transaction {} was charged back", tx_id)
+            }
+            EngineError::AccountLocked(client_id) => {
+                write!(f, "client {}'s account is locked", client_id)
+            }
+            EngineError::TxNotReversible(tx_id) => {
+                write!(f, "transaction {} cannot be reversed", tx_id)
+            }
+            EngineError::InsufficientFunds(tx_id) => write!(
+                f,
+                "reversing transaction {} would overdraw the account",
+                tx_id
+            ),
+            EngineError::MaxBalanceExceeded {
+                client_id,
+                tx_id,
+                max_balance,
+            } => write!(
+                f,
+                "deposit {} would take client {} above the maximum balance of {}",
+                tx_id, client_id, max_balance
+            ),
+            EngineError::BelowMinimumDeposit { tx_id, min_deposit } => write!(
+                f,
+                "deposit {} is below the minimum deposit of {}",
+                tx_id, min_deposit
+            ),
+            EngineError::BelowMinimumWithdrawal {
+                tx_id,
+                min_withdrawal,
+            } => write!(
+                f,
+                "withdrawal {} is below the minimum withdrawal of {}",
+                tx_id, min_withdrawal
+            ),
+            EngineError::Ignored { tx_id, reason } => {
+                write!(f, "transaction {} was ignored: {}", tx_id, reason)
+            }
+            EngineError::ShutdownTimeout(summary) => write!(
+                f,
+                "shutdown timed out after applying {} transactions",
+                summary.transactions_accepted
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
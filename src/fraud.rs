@@ -0,0 +1,37 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+
+use super::engine::Amount;
+use super::engine::ClientId;
+use super::engine::Tx;
+
+/// How likely a transaction is to be fraudulent, from 0.0 to 1.0.
+pub type FraudScore = f64;
+
+/// What the engine knows about a client's past behaviour.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountContext {
+    pub client_id: ClientId,
+    /// Time since the client's first transaction
+    pub account_age: Duration,
+    pub tx_count: u64,
+    /// Disputes raised within the last 30 days
+    pub recent_dispute_count: u32,
+    pub avg_deposit: Amount,
+    pub avg_withdrawal: Amount,
+    pub last_activity: Option<DateTime<Utc>>,
+}
+
+pub trait FraudScorer: Send + Sync {
+    fn score(&self, tx: &Tx, context: &AccountContext) -> FraudScore;
+}
+
+/// Never suspects anything, for use in tests.
+pub struct MockFraudScorer;
+
+impl FraudScorer for MockFraudScorer {
+    fn score(&self, _tx: &Tx, _context: &AccountContext) -> FraudScore {
+        0.0
+    }
+}
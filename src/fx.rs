@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+/// An ISO 4217 currency code, e.g. `USD`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const USD: Currency = Currency(*b"USD");
+    pub const EUR: Currency = Currency(*b"EUR");
+    pub const GBP: Currency = Currency(*b"GBP");
+
+    pub fn code(&self) -> &str {
+        // Only ASCII letters ever make it into a Currency
+        std::str::from_utf8(&self.0).expect("currency code is not ASCII")
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Self::USD
+    }
+}
+
+impl FromStr for Currency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let bytes = s.as_bytes();
+        if bytes.len() != 3 || !bytes.iter().all(u8::is_ascii_alphabetic) {
+            return Err(anyhow::anyhow!("invalid currency code {:?}", s));
+        }
+        let mut code = [0; 3];
+        code.copy_from_slice(bytes);
+        code.make_ascii_uppercase();
+        Ok(Self(code))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        code.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+pub trait ExchangeRateProvider: Send + Sync {
+    /// How many units of `to` one unit of `from` buys.
+    fn rate(&self, from: Currency, to: Currency) -> anyhow::Result<Decimal>;
+}
+
+/// Serves a fixed table of rates, for use in tests.
+#[derive(Default)]
+pub struct MockExchangeRateProvider {
+    rates: HashMap<(Currency, Currency), Decimal>,
+}
+
+impl MockExchangeRateProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rate(mut self, from: Currency, to: Currency, rate: Decimal) -> Self {
+        self.rates.insert((from, to), rate);
+        self
+    }
+}
+
+impl ExchangeRateProvider for MockExchangeRateProvider {
+    fn rate(&self, from: Currency, to: Currency) -> anyhow::Result<Decimal> {
+        if from == to {
+            return Ok(dec!(1));
+        }
+        self.rates
+            .get(&(from, to))
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("no exchange rate from {} to {}", from, to))
+    }
+}
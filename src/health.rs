@@ -0,0 +1,28 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthStatus {
+    /// False if any check failed. Warnings still count as healthy.
+    pub ok: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthStatus {
+    pub fn from_checks(checks: Vec<HealthCheck>) -> Self {
+        Self {
+            ok: checks.iter().all(|check| check.status != CheckStatus::Fail),
+            checks,
+        }
+    }
+}
@@ -0,0 +1,11 @@
+pub mod alert;
+pub mod audit;
+pub mod clock;
+pub mod engine;
+pub mod error;
+pub mod fraud;
+pub mod fx;
+pub mod health;
+pub mod persistence;
+pub mod reader;
+pub mod report;
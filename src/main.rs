@@ -1,40 +1,57 @@
 use std::env;
 
-use tokio::sync::mpsc::channel;
-use tokio::sync::mpsc::Receiver;
-use tokio::sync::mpsc::Sender;
-use tokio_stream::wrappers::ReceiverStream;
+use payengine::engine::PaymentsEngineBuilder;
+use payengine::reader::AmountPrecision;
+use payengine::report::ReportFormat;
 
-mod engine;
-mod reader;
-
-use engine::PaymentsEngine;
-use engine::Tx;
-
-use reader::fetch_csv_data;
-const CHANNEL_SIZE: usize = 10000;
+/// Splits the arguments into the report format, the amount precision and
+/// the input file names.
+fn parse_args(
+    mut args: impl Iterator<Item = String>,
+) -> anyhow::Result<(ReportFormat, AmountPrecision, Vec<String>)> {
+    let mut format = ReportFormat::default();
+    let mut amount_precision = AmountPrecision::default();
+    let mut filenames = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("{} expects a value", arg))
+        };
+        match arg.as_str() {
+            "--format" => format = value()?.parse()?,
+            "--amount-precision" => amount_precision = value()?.parse()?,
+            _ => filenames.push(arg),
+        }
+    }
+    Ok((format, amount_precision, filenames))
+}
 
 #[tokio::main]
 async fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 2 {
-        eprintln!("Too many arguments.")
-    } else if let Some(filename) = args.get(1) {
-        let (sender, receiver): (Sender<Tx>, Receiver<Tx>) = channel(CHANNEL_SIZE);
-        let receiver = ReceiverStream::new(receiver);
-        let filename = filename.to_string();
-        let mut engine = PaymentsEngine::new(receiver);
-        tokio::spawn(async move {
-            if let Err(e) = engine.process_txs().await {
-                eprintln!("Error processing txs: {}", e);
-                return;
-            };
-            engine.print_report()
-        });
-        if let Err(e) = fetch_csv_data(&filename, sender).await {
-            eprintln!("Error fetching csv data {}", e)
+    let (format, amount_precision, filenames) = match parse_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    if filenames.is_empty() {
+        eprintln!("Expected to receive at least one file name as input");
+        return;
+    }
+    let mut engine = PaymentsEngineBuilder::new()
+        .amount_precision(amount_precision)
+        .build(tokio_stream::empty())
+        .with_report_format(format);
+    tokio::select! {
+        result = engine.process_txs_from_file(&filenames) => {
+            // The report still covers whatever was applied
+            if let Err(e) = result {
+                eprintln!("Error processing txs: {:#}", e)
+            }
         }
-    } else {
-        eprintln!("Expected to receive a file name as input")
+        // Transactions read but not yet applied are dropped
+        _ = tokio::signal::ctrl_c() => {}
     }
+    engine.print_report()
 }
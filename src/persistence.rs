@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use super::engine::ClientAccountSnapshot;
+use super::engine::ClientId;
+use super::engine::Tx;
+use super::engine::TxId;
+
+/// Somewhere to keep accounts and transactions beyond the life of an
+/// engine. Saving an account or transaction that is already stored
+/// replaces it.
+pub trait PersistenceBackend: Send {
+    fn save_account(&mut self, account: &ClientAccountSnapshot) -> anyhow::Result<()>;
+    fn save_tx(&mut self, tx: &Tx) -> anyhow::Result<()>;
+    fn load_all_accounts(&self) -> anyhow::Result<Vec<ClientAccountSnapshot>>;
+    fn load_all_txs(&self) -> anyhow::Result<Vec<Tx>>;
+}
+
+/// Keeps everything in memory, for tests that should not touch the file
+/// system.
+#[derive(Default)]
+pub struct InMemoryBackend {
+    accounts: HashMap<ClientId, ClientAccountSnapshot>,
+    txs: HashMap<TxId, Tx>,
+}
+
+impl InMemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PersistenceBackend for InMemoryBackend {
+    fn save_account(&mut self, account: &ClientAccountSnapshot) -> anyhow::Result<()> {
+        self.accounts.insert(account.client_id, account.clone());
+        Ok(())
+    }
+
+    fn save_tx(&mut self, tx: &Tx) -> anyhow::Result<()> {
+        self.txs.insert(tx.tx_id, tx.clone());
+        Ok(())
+    }
+
+    fn load_all_accounts(&self) -> anyhow::Result<Vec<ClientAccountSnapshot>> {
+        Ok(self.accounts.values().cloned().collect())
+    }
+
+    fn load_all_txs(&self) -> anyhow::Result<Vec<Tx>> {
+        Ok(self.txs.values().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TxInner;
+    use crate::fx::Currency;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn saved_records_are_loaded_back() {
+        let mut backend = InMemoryBackend::new();
+        let account = ClientAccountSnapshot {
+            client_id: 1,
+            available: dec!(1.5),
+            held: dec!(0.5),
+            total: dec!(2.0),
+            locked: false,
+            currency: Currency::USD,
+        };
+        let tx = Tx {
+            client_id: 1,
+            tx_id: 7,
+            inner: TxInner::Deposit { amount: dec!(2.0) },
+        };
+        backend.save_account(&account).unwrap();
+        backend.save_tx(&tx).unwrap();
+        // Saving again replaces rather than duplicates
+        backend.save_tx(&tx).unwrap();
+
+        assert_eq!(backend.load_all_accounts().unwrap(), vec![account]);
+        assert_eq!(backend.load_all_txs().unwrap(), vec![tx]);
+    }
+}
@@ -1,32 +1,460 @@
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
 use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use anyhow::Context;
+use rust_decimal::Decimal;
 use serde::Deserialize;
 use tokio::sync::mpsc::Sender;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
 
 use super::engine::Amount;
 use super::engine::ClientId;
 use super::engine::Tx;
 use super::engine::TxId;
 use super::engine::TxInner;
+use super::engine::CHANNEL_SIZE;
+use super::fx::Currency;
+
+const MAX_DECIMAL_PLACES: u32 = 4;
+
+/// What to do with amounts given to more than four decimal places.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AmountPrecision {
+    /// Reject the record
+    Strict,
+    /// Round to four decimal places
+    #[default]
+    Lenient,
+}
+
+impl FromStr for AmountPrecision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "strict" => Ok(AmountPrecision::Strict),
+            "lenient" => Ok(AmountPrecision::Lenient),
+            _ => Err(anyhow::anyhow!(
+                "unknown amount precision {:?}, expected strict or lenient",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub amount_precision: AmountPrecision,
+    /// Whether the first row names the columns. Without one the columns
+    /// are taken to be `type,client,tx,amount,to_client`
+    pub has_header: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            amount_precision: AmountPrecision::default(),
+            has_header: true,
+        }
+    }
+}
 
 pub async fn fetch_csv_data(filename: impl AsRef<Path>, sender: Sender<Tx>) -> anyhow::Result<()> {
-    let mut csv_reader = csv::Reader::from_path(filename)?;
-    for record in csv_reader.records() {
-        let mut record = record.context("getting CSV Record")?;
-        // We trim whitespaces so that serde will be able to Deserialize
-        // our record into a Tx struct
-        csv::StringRecord::trim(&mut record);
-        let parsed_tx = record
-            .deserialize(None)
-            .context("Deserializing record into Tx")?;
-        let tx = FromParsedTx::from_parsed(parsed_tx)?;
-
-        sender.send(tx).await?;
+    InputSource::new(filename.as_ref(), InputFormat::Csv)
+        .send_to(sender)
+        .await
+}
+
+pub async fn fetch_csv_data_from_reader<R: Read + Send + 'static>(
+    reader: R,
+    sender: Sender<Tx>,
+) -> anyhow::Result<()> {
+    fetch_csv_data_with_options(reader, sender, CsvOptions::default()).await
+}
+
+pub async fn fetch_csv_data_with_options<R: Read + Send + 'static>(
+    reader: R,
+    sender: Sender<Tx>,
+    options: CsvOptions,
+) -> anyhow::Result<()> {
+    // Reading and parsing block, so they run on the blocking pool
+    tokio::task::spawn_blocking(move || {
+        fetch_csv_data_blocking_with_options(reader, sender, options)
+    })
+    .await?
+}
+
+/// Sends every transaction in CSV `reader` from a thread that may block,
+/// such as a `spawn_blocking` task, rather than from async code.
+pub fn fetch_csv_data_blocking<R: Read>(reader: R, sender: Sender<Tx>) -> anyhow::Result<()> {
+    fetch_csv_data_blocking_with_options(reader, sender, CsvOptions::default())
+}
+
+pub fn fetch_csv_data_blocking_with_options<R: Read>(
+    reader: R,
+    sender: Sender<Tx>,
+    options: CsvOptions,
+) -> anyhow::Result<()> {
+    for tx in parse_delimited(reader, b',', options) {
+        sender.blocking_send(tx?)?;
     }
     Ok(())
 }
 
+/// Reads tab-separated values with the same columns as the CSV input.
+pub async fn fetch_tsv_data(filename: impl AsRef<Path>, sender: Sender<Tx>) -> anyhow::Result<()> {
+    InputSource::new(filename.as_ref(), InputFormat::Tsv)
+        .send_to(sender)
+        .await
+}
+
+/// Reads one JSON object per line, keyed by the CSV column names.
+pub async fn fetch_jsonl_data(
+    filename: impl AsRef<Path>,
+    sender: Sender<Tx>,
+) -> anyhow::Result<()> {
+    InputSource::new(filename.as_ref(), InputFormat::JsonLines)
+        .send_to(sender)
+        .await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Csv,
+    Tsv,
+    /// One JSON object per line, with the same fields as the CSV columns
+    JsonLines,
+}
+
+pub struct InputSource {
+    path: PathBuf,
+    format: InputFormat,
+    options: CsvOptions,
+}
+
+impl InputSource {
+    pub fn new(path: impl Into<PathBuf>, format: InputFormat) -> Self {
+        Self {
+            path: path.into(),
+            format,
+            options: CsvOptions::default(),
+        }
+    }
+
+    /// Picks the format from the file extension: `.tsv` is TSV, `.jsonl`
+    /// and `.ndjson` are JSON lines, and anything else is CSV.
+    pub fn detect(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let format = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("tsv") => InputFormat::Tsv,
+            Some("jsonl") | Some("ndjson") => InputFormat::JsonLines,
+            _ => InputFormat::Csv,
+        };
+        Self::new(path, format)
+    }
+
+    /// Options for reading the file. Only amount precision applies to
+    /// JSON lines.
+    pub fn with_options(mut self, options: CsvOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn format(&self) -> InputFormat {
+        self.format
+    }
+
+    /// Yields the transactions in the file in order. A file that cannot
+    /// be opened yields a single error. Must be called from within a
+    /// Tokio runtime.
+    pub fn into_stream(self) -> impl Stream<Item = anyhow::Result<Tx>> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(CHANNEL_SIZE);
+        // The parsers are synchronous, so they run on the blocking pool
+        // rather than stall a runtime worker
+        tokio::task::spawn_blocking(move || {
+            let txs: TxIter = match self.open() {
+                Ok(txs) => txs,
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            };
+            for tx in txs {
+                // The stream has been dropped, so nothing wants the rest
+                if sender.blocking_send(tx).is_err() {
+                    break;
+                }
+            }
+        });
+        ReceiverStream::new(receiver)
+    }
+
+    /// Sends every transaction in the file, stopping at the first one that
+    /// cannot be read.
+    pub async fn send_to(self, sender: Sender<Tx>) -> anyhow::Result<()> {
+        let context = format!("reading {}", self.path.display());
+        // The parsers are synchronous, so they run on the blocking pool
+        // rather than stall a runtime worker
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let txs = self.open().with_context(|| context.clone())?;
+            for tx in txs {
+                sender.blocking_send(tx.with_context(|| context.clone())?)?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    fn open(&self) -> anyhow::Result<TxIter> {
+        Ok(parse_input(
+            File::open(&self.path)?,
+            self.format,
+            self.options.clone(),
+        ))
+    }
+}
+
+/// Reads a CSV file through a memory map rather than buffered reads,
+/// which can be faster for very large files.
+#[cfg(feature = "mmap")]
+pub struct MmapCsvSource {
+    map: memmap2::Mmap,
+    options: CsvOptions,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapCsvSource {
+    pub fn new(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("reading {}", path.display()))?;
+        // Safety: the map is only ever read. The file must not be truncated
+        // or written to while the source is alive, as for any mapped file
+        let map = unsafe { memmap2::Mmap::map(&file) }
+            .with_context(|| format!("mapping {}", path.display()))?;
+        Ok(Self {
+            map,
+            options: CsvOptions::default(),
+        })
+    }
+
+    pub fn with_options(mut self, options: CsvOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Yields the transactions in the file in order.
+    pub fn into_stream(self) -> impl Stream<Item = anyhow::Result<Tx>> {
+        tokio_stream::iter(parse_delimited(
+            std::io::Cursor::new(self.map),
+            b',',
+            self.options,
+        ))
+    }
+}
+
+fn parse_input<R: Read + Send + 'static>(
+    reader: R,
+    format: InputFormat,
+    options: CsvOptions,
+) -> TxIter {
+    match format {
+        InputFormat::Csv => Box::new(parse_delimited(reader, b',', options)),
+        InputFormat::Tsv => Box::new(parse_delimited(reader, b'\t', options)),
+        InputFormat::JsonLines => Box::new(parse_json_lines(reader, options)),
+    }
+}
+
+type TxIter = Box<dyn Iterator<Item = anyhow::Result<Tx>> + Send>;
+
+fn parse_delimited<R: Read>(
+    reader: R,
+    delimiter: u8,
+    options: CsvOptions,
+) -> impl Iterator<Item = anyhow::Result<Tx>> {
+    // Only transfers fill in the trailing to_client column, so rows are
+    // allowed to omit it. We trim whitespaces, headers included, so that
+    // serde will be able to Deserialize our records into a Tx struct
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .has_headers(options.has_header)
+        .from_reader(reader);
+    // Fields are matched to the header row by name, so the columns may
+    // come in any order
+    let headers = if options.has_header {
+        reader.byte_headers().cloned()
+    } else {
+        Ok(csv::ByteRecord::from(vec![
+            "type", "client", "tx", "amount",
+        ]))
+    };
+    let columns = headers
+        .context("reading CSV header")
+        .and_then(|headers| Columns::find(&with_to_client_header(headers)));
+    let (columns, header_error) = match columns {
+        Ok(columns) => (Some(columns), None),
+        Err(e) => (None, Some(Err(e))),
+    };
+    // Every record is read into the same buffer and its fields parsed
+    // straight from their bytes, so no record allocates once the buffer
+    // has grown to fit the longest row
+    let records = columns.map(|columns| {
+        let mut record = csv::ByteRecord::new();
+        std::iter::from_fn(move || match reader.read_byte_record(&mut record) {
+            Ok(false) => None,
+            Ok(true) => Some(
+                columns
+                    .parse(&record)
+                    .context("Deserializing record into Tx")
+                    .and_then(|parsed_tx| Tx::from_parsed(parsed_tx, options.amount_precision)),
+            ),
+            Err(e) => Some(Err(anyhow::Error::new(e).context("getting CSV Record"))),
+        })
+    });
+    header_error
+        .into_iter()
+        .chain(records.into_iter().flatten())
+}
+
+/// Where each column sits in a delimited record.
+struct Columns {
+    tx_type: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    to_client: Option<usize>,
+    from_currency: Option<usize>,
+    to_currency: Option<usize>,
+    rate: Option<usize>,
+}
+
+impl Columns {
+    fn find(headers: &csv::ByteRecord) -> anyhow::Result<Self> {
+        let find = |name: &str| headers.iter().position(|header| header == name.as_bytes());
+        let require =
+            |name| find(name).ok_or_else(|| anyhow::anyhow!("CSV header has no {} column", name));
+        Ok(Self {
+            tx_type: require("type")?,
+            client: require("client")?,
+            tx: require("tx")?,
+            amount: find("amount"),
+            to_client: find("to_client"),
+            from_currency: find("from_currency"),
+            to_currency: find("to_currency"),
+            rate: find("rate"),
+        })
+    }
+
+    fn parse(&self, record: &csv::ByteRecord) -> anyhow::Result<ParsedTx> {
+        let required = |column, name| {
+            field(record, Some(column)).ok_or_else(|| anyhow::anyhow!("record has no {}", name))
+        };
+        Ok(ParsedTx {
+            tx_type: TxType::from_bytes(required(self.tx_type, "type")?)?,
+            client_id: parse_field(required(self.client, "client")?, "client")?,
+            tx_id: parse_field(required(self.tx, "tx")?, "tx")?,
+            amount: field(record, self.amount)
+                .map(parse_amount)
+                .transpose()?
+                // Trailing zeros carry no precision, e.g. 1.0 is 1
+                .map(|amount| amount.normalize()),
+            to_client_id: field(record, self.to_client)
+                .map(|to_client| parse_field(to_client, "to_client"))
+                .transpose()?,
+            from_currency: field(record, self.from_currency)
+                .map(|currency| parse_field(currency, "from_currency"))
+                .transpose()?,
+            to_currency: field(record, self.to_currency)
+                .map(|currency| parse_field(currency, "to_currency"))
+                .transpose()?,
+            rate: field(record, self.rate)
+                .map(|rate| parse_field(rate, "rate"))
+                .transpose()?,
+        })
+    }
+}
+
+// Missing and empty fields both read as no value
+fn field(record: &csv::ByteRecord, column: Option<usize>) -> Option<&[u8]> {
+    column
+        .and_then(|column| record.get(column))
+        .filter(|field| !field.is_empty())
+}
+
+// Most amounts are short plain decimals such as 12.3456, which are read
+// here without going through a string; anything else, including
+// exponents and numbers too long for an i64, is left to Decimal::from_str
+fn parse_amount(field: &[u8]) -> anyhow::Result<Amount> {
+    const MAX_FAST_DIGITS: usize = 18;
+    let (negative, digits) = match field.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, field),
+    };
+    let mut mantissa: i64 = 0;
+    let mut scale = None;
+    let mut digit_count = 0;
+    for (i, &byte) in digits.iter().enumerate() {
+        match byte {
+            b'0'..=b'9' if digit_count < MAX_FAST_DIGITS => {
+                mantissa = mantissa * 10 + i64::from(byte - b'0');
+                digit_count += 1;
+            }
+            b'.' if scale.is_none() => scale = Some(digits.len() - i - 1),
+            _ => return parse_field(field, "amount"),
+        }
+    }
+    // A leading or trailing point is left to from_str to accept or not
+    if digit_count == 0 || scale == Some(0) || digits.first() == Some(&b'.') {
+        return parse_field(field, "amount");
+    }
+    let mantissa = if negative { -mantissa } else { mantissa };
+    Ok(Amount::new(mantissa, scale.unwrap_or(0) as u32))
+}
+
+fn parse_field<F>(field: &[u8], name: &str) -> anyhow::Result<F>
+where
+    F: FromStr,
+    F::Err: Into<anyhow::Error>,
+{
+    std::str::from_utf8(field)
+        .with_context(|| format!("{} is not UTF-8", name))?
+        .parse()
+        .map_err(Into::<anyhow::Error>::into)
+        .with_context(|| format!("invalid {} {:?}", name, String::from_utf8_lossy(field)))
+}
+
+// A transfer's recipient may follow the amount even when the header row
+// does not name that column
+fn with_to_client_header(mut headers: csv::ByteRecord) -> csv::ByteRecord {
+    if !headers.iter().any(|header| header == b"to_client") {
+        headers.push_field(b"to_client");
+    }
+    headers
+}
+
+fn parse_json_lines<R: Read>(
+    reader: R,
+    options: CsvOptions,
+) -> impl Iterator<Item = anyhow::Result<Tx>> {
+    BufReader::new(reader)
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(move |line| {
+            let line = line.context("reading JSON line")?;
+            let parsed_tx =
+                serde_json::from_str(&line).context("Deserializing JSON line into Tx")?;
+            Tx::from_parsed(parsed_tx, options.amount_precision)
+        })
+}
+
 #[derive(Debug, PartialEq, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum TxType {
@@ -35,6 +463,28 @@ enum TxType {
     Dispute,
     Resolve,
     Chargeback,
+    Transfer,
+    Fee,
+    FxTransfer,
+}
+
+impl TxType {
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        match bytes {
+            b"deposit" => Ok(TxType::Deposit),
+            b"withdrawal" => Ok(TxType::Withdrawal),
+            b"dispute" => Ok(TxType::Dispute),
+            b"resolve" => Ok(TxType::Resolve),
+            b"chargeback" => Ok(TxType::Chargeback),
+            b"transfer" => Ok(TxType::Transfer),
+            b"fee" => Ok(TxType::Fee),
+            b"fx_transfer" => Ok(TxType::FxTransfer),
+            _ => Err(anyhow::anyhow!(
+                "unknown transaction type {:?}",
+                String::from_utf8_lossy(bytes)
+            )),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
@@ -46,29 +496,94 @@ struct ParsedTx {
     #[serde(rename = "tx")]
     tx_id: TxId,
     amount: Option<Amount>,
+    #[serde(rename = "to_client", default)]
+    to_client_id: Option<ClientId>,
+    #[serde(default)]
+    from_currency: Option<Currency>,
+    #[serde(default)]
+    to_currency: Option<Currency>,
+    #[serde(default)]
+    rate: Option<Decimal>,
+}
+
+fn check_precision(
+    amount: Amount,
+    tx_id: TxId,
+    precision: AmountPrecision,
+) -> anyhow::Result<Amount> {
+    if amount.scale() <= MAX_DECIMAL_PLACES {
+        return Ok(amount);
+    }
+    match precision {
+        AmountPrecision::Strict => Err(anyhow::anyhow!(
+            "transaction {} has amount {} with more than {} decimal places",
+            tx_id,
+            amount,
+            MAX_DECIMAL_PLACES
+        )),
+        AmountPrecision::Lenient => Ok(amount.round_dp(MAX_DECIMAL_PLACES)),
+    }
 }
 
 trait FromParsedTx {
-    fn from_parsed(tx: ParsedTx) -> anyhow::Result<Self>
+    fn from_parsed(tx: ParsedTx, precision: AmountPrecision) -> anyhow::Result<Self>
     where
         Self: std::marker::Sized;
 }
 
 impl FromParsedTx for Tx {
-    fn from_parsed(tx: ParsedTx) -> anyhow::Result<Self> {
+    fn from_parsed(tx: ParsedTx, precision: AmountPrecision) -> anyhow::Result<Self> {
+        let amount = tx
+            .amount
+            .map(|amount| check_precision(amount, tx.tx_id, precision))
+            .transpose()?;
         let inner = match tx.tx_type {
             TxType::Withdrawal => {
-                let amount = tx.amount.ok_or_else(|| {
+                let amount = amount.ok_or_else(|| {
                     anyhow::anyhow!("transaction {} is a withdrawal without an amount", tx.tx_id)
                 })?;
                 TxInner::Withdrawal { amount }
             }
             TxType::Deposit => {
-                let amount = tx.amount.ok_or_else(|| {
-                    anyhow::anyhow!("transaction {} is a withdrawal without an amount", tx.tx_id)
+                let amount = amount.ok_or_else(|| {
+                    anyhow::anyhow!("transaction {} is a deposit without an amount", tx.tx_id)
                 })?;
                 TxInner::Deposit { amount }
             }
+            TxType::Transfer => {
+                let amount = amount.ok_or_else(|| {
+                    anyhow::anyhow!("transaction {} is a transfer without an amount", tx.tx_id)
+                })?;
+                let to_client_id = tx.to_client_id.ok_or_else(|| {
+                    anyhow::anyhow!("transaction {} is a transfer without a recipient", tx.tx_id)
+                })?;
+                TxInner::Transfer {
+                    to_client_id,
+                    amount,
+                }
+            }
+            TxType::Fee => {
+                let amount = amount.ok_or_else(|| {
+                    anyhow::anyhow!("transaction {} is a fee without an amount", tx.tx_id)
+                })?;
+                TxInner::Fee { amount }
+            }
+            TxType::FxTransfer => {
+                let missing = |column| {
+                    anyhow::anyhow!(
+                        "transaction {} is an fx_transfer without {}",
+                        tx.tx_id,
+                        column
+                    )
+                };
+                TxInner::FxTransfer {
+                    to_client: tx.to_client_id.ok_or_else(|| missing("a recipient"))?,
+                    amount: amount.ok_or_else(|| missing("an amount"))?,
+                    from_currency: tx.from_currency.ok_or_else(|| missing("a from_currency"))?,
+                    to_currency: tx.to_currency.ok_or_else(|| missing("a to_currency"))?,
+                    rate: tx.rate.ok_or_else(|| missing("a rate"))?,
+                }
+            }
             TxType::Chargeback => TxInner::Chargeback,
             TxType::Dispute => TxInner::Dispute,
             TxType::Resolve => TxInner::Resolve,
@@ -84,6 +599,9 @@ impl FromParsedTx for Tx {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+    use rust_decimal_macros::dec;
+
     #[test]
     fn deserialize_record() {
         let record = csv::StringRecord::from(vec!["deposit", "1", "1", "1.0"]);
@@ -94,8 +612,418 @@ mod tests {
                 tx_type: TxType::Deposit,
                 client_id: 1,
                 tx_id: 1,
-                amount: Some(1.0)
+                amount: Some(dec!(1.0)),
+                to_client_id: None,
+                from_currency: None,
+                to_currency: None,
+                rate: None,
             }
         )
     }
+
+    #[test]
+    fn csv_columns_may_come_in_any_order() {
+        for input in [
+            "tx,type,client,amount\n1,deposit,2,1.5\n",
+            "amount,tx,client,type\n1.5,1,2,deposit\n",
+            " client , amount , type , tx\n 2 , 1.5 , deposit , 1\n",
+        ] {
+            let txs: Vec<Tx> = parse_delimited(input.as_bytes(), b',', CsvOptions::default())
+                .collect::<anyhow::Result<_>>()
+                .expect("failed to parse");
+            assert_eq!(
+                txs,
+                vec![Tx {
+                    client_id: 2,
+                    tx_id: 1,
+                    inner: TxInner::Deposit { amount: dec!(1.5) },
+                }],
+                "input: {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn csv_transfer_recipient_needs_no_header() {
+        let input = "type,client,tx,amount\ntransfer,1,1,2.0,2\n";
+        let txs: Vec<Tx> = parse_delimited(input.as_bytes(), b',', CsvOptions::default())
+            .collect::<anyhow::Result<_>>()
+            .expect("failed to parse");
+        assert_eq!(
+            txs[0].inner,
+            TxInner::Transfer {
+                to_client_id: 2,
+                amount: dec!(2.0)
+            }
+        );
+    }
+
+    #[test]
+    fn csv_fx_transfer_reads_currencies_and_rate() {
+        let input = "type,client,tx,amount,to_client,from_currency,to_currency,rate\n\
+                     fx_transfer,1,1,10.0,2,usd,EUR,0.92\n\
+                     deposit,1,2,1.0,,,,\n";
+        let txs: Vec<Tx> = parse_delimited(input.as_bytes(), b',', CsvOptions::default())
+            .collect::<anyhow::Result<_>>()
+            .expect("failed to parse");
+        assert_eq!(
+            txs[0].inner,
+            TxInner::FxTransfer {
+                to_client: 2,
+                amount: dec!(10.0),
+                from_currency: Currency::USD,
+                to_currency: Currency::EUR,
+                rate: dec!(0.92),
+            }
+        );
+        assert_eq!(txs[1].inner, TxInner::Deposit { amount: dec!(1.0) });
+
+        for input in [
+            "type,client,tx,amount,to_client\nfx_transfer,1,1,10.0,2\n",
+            "type,client,tx,amount,from_currency,to_currency,rate\nfx_transfer,1,1,10.0,USD,EUR,0.92\n",
+            "type,client,tx,amount,to_client,from_currency,to_currency,rate\nfx_transfer,1,1,10.0,2,US,EUR,0.92\n",
+        ] {
+            let result: anyhow::Result<Vec<Tx>> =
+                parse_delimited(input.as_bytes(), b',', CsvOptions::default()).collect();
+            assert!(result.is_err(), "input: {:?}", input);
+        }
+    }
+
+    #[test]
+    fn csv_tx_ids_may_exceed_u32() {
+        let input = "type,client,tx,amount\ndeposit,1,4294967296,1.0\n";
+        let txs: Vec<Tx> = parse_delimited(input.as_bytes(), b',', CsvOptions::default())
+            .collect::<anyhow::Result<_>>()
+            .expect("failed to parse");
+        assert_eq!(txs[0].tx_id, 1 << 32);
+    }
+
+    #[test]
+    fn parse_amount_handles_edge_cases() {
+        for input in [
+            "1",
+            "-2.5",
+            "0.0001",
+            ".5",
+            "5.",
+            "1e3",
+            "99999999999999999999.1234",
+        ] {
+            assert_eq!(
+                parse_amount(input.as_bytes()).ok(),
+                Amount::from_str(input).ok(),
+                "input: {:?}",
+                input
+            );
+        }
+        assert!(parse_amount(b"").is_err());
+        assert!(parse_amount(b"-").is_err());
+        assert!(parse_amount(b"1.2.3").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn parse_amount_matches_from_str(n in any::<i64>(), scale in 0u32..=8) {
+            let text = Amount::new(n, scale).to_string();
+            prop_assert_eq!(
+                parse_amount(text.as_bytes()).ok(),
+                Amount::from_str(&text).ok()
+            );
+        }
+    }
+
+    #[test]
+    fn tx_type_from_bytes() {
+        assert_eq!(
+            TxType::from_bytes(b"chargeback").unwrap(),
+            TxType::Chargeback
+        );
+        assert!(TxType::from_bytes(b"Deposit").is_err());
+    }
+
+    #[test]
+    fn csv_rejects_invalid_fields() {
+        for input in [
+            "type,client,tx,amount\nrefund,1,1,1.0\n",
+            "type,client,tx,amount\ndeposit,x,1,1.0\n",
+            "type,client,tx,amount\ndeposit,1,1,one\n",
+            "type,client,amount\ndeposit,1,1.0\n",
+        ] {
+            let result: anyhow::Result<Vec<Tx>> =
+                parse_delimited(input.as_bytes(), b',', CsvOptions::default()).collect();
+            assert!(result.is_err(), "input: {:?}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn input_source_streams_the_file() {
+        use tokio_stream::StreamExt;
+
+        let file = tempfile::NamedTempFile::new().expect("failed to create temp file");
+        std::fs::write(
+            file.path(),
+            "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,x,2,1.0\ndispute,1,1,\n",
+        )
+        .expect("failed to write input");
+        let txs: Vec<_> = InputSource::new(file.path(), InputFormat::Csv)
+            .into_stream()
+            .collect()
+            .await;
+        assert_eq!(txs.len(), 3);
+        assert_eq!(
+            txs[0].as_ref().expect("failed to parse").inner,
+            TxInner::Deposit { amount: dec!(2.0) }
+        );
+        assert!(txs[1].is_err());
+        assert_eq!(
+            txs[2].as_ref().expect("failed to parse").inner,
+            TxInner::Dispute
+        );
+
+        let missing: Vec<_> =
+            InputSource::new(file.path().with_extension("missing"), InputFormat::Csv)
+                .into_stream()
+                .collect()
+                .await;
+        assert_eq!(missing.len(), 1);
+        assert!(missing[0].is_err());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[tokio::test]
+    async fn mmap_source_reads_csv() {
+        use tokio_stream::StreamExt;
+
+        let file = tempfile::NamedTempFile::new().expect("failed to create temp file");
+        std::fs::write(file.path(), "type,client,tx,amount\ndeposit,1,1,2.0\n")
+            .expect("failed to write input");
+        let txs: Vec<_> = MmapCsvSource::new(file.path())
+            .expect("failed to map input")
+            .into_stream()
+            .collect()
+            .await;
+        assert_eq!(txs.len(), 1);
+        assert_eq!(
+            txs[0].as_ref().expect("failed to parse").inner,
+            TxInner::Deposit { amount: dec!(2.0) }
+        );
+    }
+
+    #[test]
+    fn csv_without_header() {
+        let options = CsvOptions {
+            has_header: false,
+            ..CsvOptions::default()
+        };
+        let input = "deposit,1,1,2.0\ntransfer,1,2,1.0,2\n";
+        let txs: Vec<Tx> = parse_delimited(input.as_bytes(), b',', options)
+            .collect::<anyhow::Result<_>>()
+            .expect("failed to parse");
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].inner, TxInner::Deposit { amount: dec!(2.0) });
+        assert_eq!(
+            txs[1].inner,
+            TxInner::Transfer {
+                to_client_id: 2,
+                amount: dec!(1.0)
+            }
+        );
+    }
+
+    #[test]
+    fn csv_header_is_not_read_as_a_transaction() {
+        let input = "type,client,tx,amount\ndeposit,1,1,2.0\n";
+        let txs: Vec<Tx> = parse_delimited(input.as_bytes(), b',', CsvOptions::default())
+            .collect::<anyhow::Result<_>>()
+            .expect("failed to parse");
+        assert_eq!(txs.len(), 1);
+    }
+
+    #[test]
+    fn csv_rows_may_omit_trailing_columns() {
+        let input = "type,client,tx,amount,to_client\ndeposit,1,1,2.0\ndispute,1,1\n";
+        let txs: Vec<Tx> = parse_delimited(input.as_bytes(), b',', CsvOptions::default())
+            .collect::<anyhow::Result<_>>()
+            .expect("failed to parse");
+        assert_eq!(txs[1].inner, TxInner::Dispute);
+    }
+
+    fn parsed_deposit(amount: Amount) -> ParsedTx {
+        ParsedTx {
+            tx_type: TxType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(amount),
+            to_client_id: None,
+            from_currency: None,
+            to_currency: None,
+            rate: None,
+        }
+    }
+
+    fn deposit_amount(tx: Tx) -> Amount {
+        match tx.inner {
+            TxInner::Deposit { amount } => amount,
+            other => panic!("expected a deposit, got {}", other),
+        }
+    }
+
+    #[test]
+    fn parse_json_lines_skips_blank_lines() {
+        let input = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 2.5}\n\n\
+                     {\"type\": \"dispute\", \"client\": 1, \"tx\": 1}\n";
+        let txs: Vec<Tx> = parse_json_lines(input.as_bytes(), CsvOptions::default())
+            .collect::<anyhow::Result<_>>()
+            .expect("failed to parse");
+        assert_eq!(
+            txs,
+            vec![
+                Tx {
+                    client_id: 1,
+                    tx_id: 1,
+                    inner: TxInner::Deposit { amount: dec!(2.5) },
+                },
+                Tx {
+                    client_id: 1,
+                    tx_id: 1,
+                    inner: TxInner::Dispute,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn deserialize_json_objects() {
+        let cases = vec![
+            (
+                r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}"#,
+                TxType::Deposit,
+                Some(dec!(1.5)),
+            ),
+            (
+                r#"{"type": "withdrawal", "client": 1, "tx": 1, "amount": "0.25"}"#,
+                TxType::Withdrawal,
+                Some(dec!(0.25)),
+            ),
+            (
+                r#"{"type": "dispute", "client": 1, "tx": 1}"#,
+                TxType::Dispute,
+                None,
+            ),
+            (
+                r#"{"type": "resolve", "client": 1, "tx": 1, "amount": null}"#,
+                TxType::Resolve,
+                None,
+            ),
+            (
+                r#"{"type": "chargeback", "client": 1, "tx": 1}"#,
+                TxType::Chargeback,
+                None,
+            ),
+        ];
+        for (json, tx_type, amount) in cases {
+            let transaction: ParsedTx = serde_json::from_str(json).expect("failed to deserialize");
+            assert_eq!(
+                transaction,
+                ParsedTx {
+                    tx_type,
+                    client_id: 1,
+                    tx_id: 1,
+                    amount,
+                    to_client_id: None,
+                    from_currency: None,
+                    to_currency: None,
+                    rate: None,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn detect_input_format_from_extension() {
+        assert_eq!(InputSource::detect("a.tsv").format(), InputFormat::Tsv);
+        assert_eq!(
+            InputSource::detect("a.jsonl").format(),
+            InputFormat::JsonLines
+        );
+        assert_eq!(InputSource::detect("a.csv").format(), InputFormat::Csv);
+        assert_eq!(InputSource::detect("a").format(), InputFormat::Csv);
+    }
+
+    #[test]
+    fn strict_precision_accepts_up_to_four_decimal_places() {
+        for amount in [dec!(1.123), dec!(1.1234)] {
+            let tx = Tx::from_parsed(parsed_deposit(amount), AmountPrecision::Strict)
+                .expect("failed to convert");
+            assert_eq!(deposit_amount(tx), amount);
+        }
+    }
+
+    #[test]
+    fn strict_precision_rejects_five_decimal_places() {
+        assert!(Tx::from_parsed(parsed_deposit(dec!(1.12345)), AmountPrecision::Strict).is_err());
+    }
+
+    #[test]
+    fn lenient_precision_keeps_up_to_four_decimal_places() {
+        for amount in [dec!(1.123), dec!(1.1234)] {
+            let tx = Tx::from_parsed(parsed_deposit(amount), AmountPrecision::Lenient)
+                .expect("failed to convert");
+            assert_eq!(deposit_amount(tx).scale(), amount.scale());
+        }
+    }
+
+    #[test]
+    fn lenient_precision_rounds_five_decimal_places() {
+        let tx = Tx::from_parsed(parsed_deposit(dec!(1.12346)), AmountPrecision::Lenient)
+            .expect("failed to convert");
+        let amount = deposit_amount(tx);
+        assert_eq!(amount, dec!(1.1235));
+        assert_eq!(amount.scale(), 4);
+    }
+
+    fn four_dp_amount() -> impl Strategy<Value = Amount> {
+        (-1_000_000_000i64..1_000_000_000).prop_map(|n| Amount::new(n, MAX_DECIMAL_PLACES))
+    }
+
+    proptest! {
+        #[test]
+        fn addition_and_subtraction_keep_four_decimal_places(
+            a in four_dp_amount(),
+            b in four_dp_amount(),
+        ) {
+            for result in [a + b, a - b] {
+                prop_assert!(result.scale() <= MAX_DECIMAL_PLACES);
+                let checked = check_precision(result, 1, AmountPrecision::Strict).ok();
+                prop_assert_eq!(checked, Some(result));
+            }
+        }
+
+        #[test]
+        fn lenient_precision_brings_any_arithmetic_back_to_four_decimal_places(
+            a in four_dp_amount(),
+            b in four_dp_amount(),
+        ) {
+            let mut results = vec![a + b, a - b, a * b];
+            if !b.is_zero() {
+                results.push(a / b);
+            }
+            for result in results {
+                let rounded = check_precision(result, 1, AmountPrecision::Lenient)
+                    .expect("lenient precision never fails");
+                prop_assert!(rounded.scale() <= MAX_DECIMAL_PLACES);
+            }
+        }
+
+        #[test]
+        fn lenient_precision_is_idempotent(n in any::<i64>(), scale in 0u32..=12) {
+            let once = check_precision(Amount::new(n, scale), 1, AmountPrecision::Lenient)
+                .expect("lenient precision never fails");
+            let twice = check_precision(once, 1, AmountPrecision::Lenient)
+                .expect("lenient precision never fails");
+            prop_assert_eq!(once, twice);
+            prop_assert_eq!(once.scale(), twice.scale());
+        }
+    }
 }
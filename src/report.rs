@@ -0,0 +1,225 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use super::engine::ReportRow;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    #[default]
+    Csv,
+    Json,
+    Tsv,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "csv" => Ok(ReportFormat::Csv),
+            "json" => Ok(ReportFormat::Json),
+            "tsv" => Ok(ReportFormat::Tsv),
+            _ => Err(anyhow::anyhow!(
+                "unknown report format {:?}, expected csv, json or tsv",
+                s
+            )),
+        }
+    }
+}
+
+pub trait ReportWriter {
+    fn write_header(&mut self) -> anyhow::Result<()>;
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()>;
+    /// Called once after the last row.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn report_writer<'a, W: Write + 'a>(
+    format: ReportFormat,
+    out: W,
+) -> Box<dyn ReportWriter + 'a> {
+    match format {
+        ReportFormat::Csv => Box::new(CsvReportWriter::new(out)),
+        ReportFormat::Json => Box::new(JsonReportWriter::new(out)),
+        ReportFormat::Tsv => Box::new(TsvReportWriter::new(out)),
+    }
+}
+
+pub struct CsvReportWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> CsvReportWriter<W> {
+    pub fn new(out: W) -> Self {
+        CsvReportWriter { out }
+    }
+}
+
+impl<W: Write> ReportWriter for CsvReportWriter<W> {
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        writeln!(self.out, "client,available,held,total,locked")?;
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()> {
+        writeln!(
+            self.out,
+            "{},{},{},{},{}",
+            row.client_id, row.available, row.held, row.total, row.locked
+        )?;
+        Ok(())
+    }
+}
+
+pub struct TsvReportWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TsvReportWriter<W> {
+    pub fn new(out: W) -> Self {
+        TsvReportWriter { out }
+    }
+}
+
+impl<W: Write> ReportWriter for TsvReportWriter<W> {
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        writeln!(self.out, "client\tavailable\theld\ttotal\tlocked")?;
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()> {
+        writeln!(
+            self.out,
+            "{}\t{}\t{}\t{}\t{}",
+            row.client_id, row.available, row.held, row.total, row.locked
+        )?;
+        Ok(())
+    }
+}
+
+/// Writes the rows as a single JSON array.
+pub struct JsonReportWriter<W: Write> {
+    out: W,
+    rows_written: usize,
+}
+
+impl<W: Write> JsonReportWriter<W> {
+    pub fn new(out: W) -> Self {
+        JsonReportWriter {
+            out,
+            rows_written: 0,
+        }
+    }
+}
+
+impl<W: Write> ReportWriter for JsonReportWriter<W> {
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        write!(self.out, "[")?;
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()> {
+        if self.rows_written > 0 {
+            write!(self.out, ",")?;
+        }
+        serde_json::to_writer(&mut self.out, row)?;
+        self.rows_written += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        writeln!(self.out, "]")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn rows() -> Vec<ReportRow> {
+        vec![
+            ReportRow {
+                client_id: 1,
+                available: dec!(1.5),
+                held: dec!(0.0),
+                total: dec!(1.5),
+                locked: false,
+            },
+            ReportRow {
+                client_id: 2,
+                available: dec!(2.0),
+                held: dec!(1.0),
+                total: dec!(3.0),
+                locked: true,
+            },
+        ]
+    }
+
+    fn render(format: ReportFormat) -> String {
+        let mut out = Vec::new();
+        {
+            let mut writer = report_writer(format, &mut out);
+            writer.write_header().unwrap();
+            for row in rows() {
+                writer.write_row(&row).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_report() {
+        assert_eq!(
+            render(ReportFormat::Csv),
+            "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n2,2.0,1.0,3.0,true\n"
+        );
+    }
+
+    #[test]
+    fn tsv_report() {
+        assert_eq!(
+            render(ReportFormat::Tsv),
+            "client\tavailable\theld\ttotal\tlocked\n1\t1.5\t0.0\t1.5\tfalse\n2\t2.0\t1.0\t3.0\ttrue\n"
+        );
+    }
+
+    #[test]
+    fn json_report_is_an_array_of_rows() {
+        let value: serde_json::Value =
+            serde_json::from_str(&render(ReportFormat::Json)).expect("invalid JSON");
+        let rows = value.as_array().expect("expected an array");
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["clientId"], 1);
+        assert_eq!(rows[1]["locked"], true);
+    }
+
+    #[test]
+    fn report_row_json_round_trip() {
+        let row = ReportRow {
+            client_id: 1,
+            available: dec!(1.2345),
+            held: dec!(0.5000),
+            total: dec!(1.7345),
+            locked: false,
+        };
+        let json = serde_json::to_string(&row).expect("failed to serialize");
+        assert_eq!(
+            json,
+            r#"{"clientId":1,"available":"1.2345","held":"0.5000","total":"1.7345","locked":false}"#
+        );
+        let parsed: ReportRow = serde_json::from_str(&json).expect("failed to deserialize");
+        assert_eq!(parsed, row);
+        assert_eq!(parsed.held.scale(), 4);
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("json".parse::<ReportFormat>().unwrap(), ReportFormat::Json);
+        assert!("xml".parse::<ReportFormat>().is_err());
+    }
+}
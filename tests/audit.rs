@@ -0,0 +1,67 @@
+use std::fs;
+
+use chrono::Utc;
+use rust_decimal_macros::dec;
+use tempfile::NamedTempFile;
+
+use payengine::audit::CsvAuditSink;
+use payengine::engine::PaymentsEngineBuilder;
+
+const INPUT: &str = "\
+type,client,tx,amount
+deposit,1,1,2.5
+withdrawal,1,2,5.5
+dispute,1,1,
+deposit,1,1,1.5
+deposit,2,3,0.5
+";
+
+#[tokio::test]
+async fn csv_audit_log_records_every_decision() {
+    let log = NamedTempFile::new().expect("failed to create temp file");
+    let writer = csv::Writer::from_path(log.path()).expect("failed to open audit log");
+    let mut engine = PaymentsEngineBuilder::new()
+        .min_deposit(dec!(1.0))
+        .audit_sink(Box::new(CsvAuditSink::new(writer)))
+        .build(tokio_stream::empty());
+    engine
+        .process_txs_from_reader(INPUT.as_bytes())
+        .await
+        .expect("failed to process input");
+
+    assert_eq!(
+        fs::read_to_string(log.path()).expect("failed to read audit log"),
+        "\
+type,client,tx,amount,outcome,reason
+deposit,1,1,2.5,accepted,
+withdrawal,1,2,5.5,rejected,insufficient_funds
+dispute,1,1,,accepted,
+deposit,1,1,1.5,rejected,duplicate_tx_id
+deposit,2,3,0.5,rejected,below_minimum_deposit
+"
+    );
+}
+
+#[tokio::test]
+async fn csv_audit_log_records_monthly_fees() {
+    let log = NamedTempFile::new().expect("failed to create temp file");
+    let writer = csv::Writer::from_path(log.path()).expect("failed to open audit log");
+    let mut engine = PaymentsEngineBuilder::new()
+        .audit_sink(Box::new(CsvAuditSink::new(writer)))
+        .build(tokio_stream::empty());
+    engine
+        .process_txs_from_reader(INPUT.as_bytes())
+        .await
+        .expect("failed to process input");
+    engine.monthly_fee_pass(dec!(0.25), Utc::now());
+
+    // Client 1's funds are all held, so only client 2 is charged
+    let log = fs::read_to_string(log.path()).expect("failed to read audit log");
+    assert_eq!(
+        log.lines().rev().take(2).collect::<Vec<_>>(),
+        vec![
+            "fee,2,18446744073709551615,0.25,accepted,",
+            "deposit,2,3,0.5,accepted,",
+        ]
+    );
+}
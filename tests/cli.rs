@@ -0,0 +1,54 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn binary_prints_report_for_input_file() {
+    let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("transactions.csv");
+    let output = Command::new(env!("CARGO_BIN_EXE_payengine"))
+        .arg(input)
+        .output()
+        .expect("failed to run payengine");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("output is not UTF-8"),
+        "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n2,2.0,0.0,2.0,false\n"
+    );
+}
+
+#[test]
+fn binary_prints_tsv_report() {
+    let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("transactions.csv");
+    let output = Command::new(env!("CARGO_BIN_EXE_payengine"))
+        .arg("--format")
+        .arg("tsv")
+        .arg(input)
+        .output()
+        .expect("failed to run payengine");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("output is not UTF-8"),
+        "client\tavailable\theld\ttotal\tlocked\n1\t1.5\t0.0\t1.5\tfalse\n2\t2.0\t0.0\t2.0\tfalse\n"
+    );
+}
+
+#[test]
+fn binary_rejects_overly_precise_amounts_when_strict() {
+    let mut input = tempfile::NamedTempFile::new().expect("failed to create temp file");
+    input
+        .write_all(b"type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,1,2,0.12345\n")
+        .expect("failed to write input");
+    let output = Command::new(env!("CARGO_BIN_EXE_payengine"))
+        .arg("--amount-precision")
+        .arg("strict")
+        .arg(input.path())
+        .output()
+        .expect("failed to run payengine");
+    assert!(String::from_utf8(output.stderr)
+        .expect("output is not UTF-8")
+        .contains("0.12345"));
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("output is not UTF-8"),
+        "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n"
+    );
+}
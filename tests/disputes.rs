@@ -0,0 +1,70 @@
+use rust_decimal_macros::dec;
+use test_case::test_case;
+
+use payengine::engine::Amount;
+use payengine::engine::ClientId;
+use payengine::engine::PaymentsEngine;
+use payengine::engine::ReportRow;
+use payengine::engine::Tx;
+use payengine::engine::TxId;
+use payengine::engine::TxInner;
+
+/// Deposits 10.0 for client 1 as tx 1, then applies `steps` to tx 1 and
+/// returns the client's `(available, held, total, locked)`.
+fn run(steps: &[TxInner]) -> (Amount, Amount, Amount, bool) {
+    let mut txs = vec![Tx {
+        client_id: 1,
+        tx_id: 1,
+        inner: TxInner::Deposit { amount: dec!(10.0) },
+    }];
+    txs.extend(steps.iter().map(|step| Tx {
+        client_id: 1,
+        tx_id: 1,
+        inner: step.clone(),
+    }));
+    let rows = process(txs);
+    let row = &rows[0];
+    (row.available, row.held, row.total, row.locked)
+}
+
+fn process(txs: Vec<Tx>) -> Vec<ReportRow> {
+    let mut engine = PaymentsEngine::new(tokio_stream::iter(txs));
+    tokio::runtime::Runtime::new()
+        .expect("failed to start runtime")
+        .block_on(engine.process_txs())
+        .expect("failed to process txs");
+    engine.report_rows()
+}
+
+fn tx(client_id: ClientId, tx_id: TxId, inner: TxInner) -> Tx {
+    Tx {
+        client_id,
+        tx_id,
+        inner,
+    }
+}
+
+#[test_case(&[TxInner::Dispute, TxInner::Resolve]
+    => (dec!(10.0), dec!(0.0), dec!(10.0), false); "dispute then resolve")]
+#[test_case(&[TxInner::Dispute, TxInner::Chargeback]
+    => (dec!(0.0), dec!(0.0), dec!(0.0), true); "dispute then chargeback")]
+#[test_case(&[TxInner::Dispute, TxInner::Resolve, TxInner::Dispute, TxInner::Chargeback]
+    => (dec!(0.0), dec!(0.0), dec!(0.0), true); "resolve then dispute again and charge back")]
+#[test_case(&[TxInner::Chargeback]
+    => (dec!(10.0), dec!(0.0), dec!(10.0), false); "chargeback without dispute is ignored")]
+fn dispute_lifecycle(steps: &[TxInner]) -> (Amount, Amount, Amount, bool) {
+    run(steps)
+}
+
+#[test]
+fn dispute_only_affects_the_disputing_client() {
+    let rows = process(vec![
+        tx(1, 1, TxInner::Deposit { amount: dec!(100) }),
+        tx(2, 2, TxInner::Deposit { amount: dec!(100) }),
+        tx(1, 1, TxInner::Dispute),
+        // Client 2 cannot dispute client 1's deposit
+        tx(2, 1, TxInner::Dispute),
+    ]);
+    assert_eq!((rows[0].available, rows[0].held), (dec!(0), dec!(100)));
+    assert_eq!((rows[1].available, rows[1].held), (dec!(100), dec!(0)));
+}
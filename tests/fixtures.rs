@@ -0,0 +1,53 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+
+use payengine::engine::PaymentsEngine;
+use payengine::reader::InputSource;
+
+async fn run_engine(input: PathBuf) -> String {
+    let (sender, receiver) = channel(100);
+    let mut engine = PaymentsEngine::new(ReceiverStream::new(receiver));
+    let reader = tokio::spawn(InputSource::detect(input).send_to(sender));
+    engine.process_txs().await.expect("failed to process txs");
+    reader
+        .await
+        .expect("reader task panicked")
+        .expect("failed to read input");
+    let mut report = Vec::new();
+    engine
+        .write_report(&mut report)
+        .expect("failed to write report");
+    String::from_utf8(report).expect("report is not UTF-8")
+}
+
+#[tokio::test]
+async fn fixtures_match_expected_output() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut inputs: Vec<PathBuf> = fs::read_dir(fixtures.join("input"))
+        .expect("failed to list fixture inputs")
+        .map(|entry| entry.expect("failed to read fixture entry").path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "csv" || ext == "tsv" || ext == "jsonl")
+        })
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no fixtures found");
+
+    for input in inputs {
+        let expected_path = fixtures.join("expected").join(
+            input
+                .with_extension("csv")
+                .file_name()
+                .expect("fixture without a file name"),
+        );
+        let expected = fs::read_to_string(&expected_path)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", expected_path.display(), e));
+        let actual = run_engine(input.clone()).await;
+        assert_eq!(actual, expected, "fixture {}", input.display());
+    }
+}
@@ -0,0 +1,156 @@
+use std::io::Write;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use tempfile::NamedTempFile;
+
+use payengine::engine::PaymentsEngineBuilder;
+
+const INPUT: &str = "\
+type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,3.0
+deposit,2,3,5.0
+dispute,2,3,
+deposit,3,4,4.0
+dispute,3,4,
+resolve,3,4,
+deposit,4,5,6.0
+deposit,4,6,2.0
+dispute,4,5,
+chargeback,4,5,
+deposit,5,7,1.5
+withdrawal,5,8,2.0
+";
+
+#[derive(Debug, PartialEq)]
+struct Row {
+    client: u16,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+fn parse_report(report: &[u8]) -> Vec<Row> {
+    csv::Reader::from_reader(report)
+        .records()
+        .map(|record| {
+            let record = record.expect("invalid report row");
+            Row {
+                client: record[0].parse().expect("invalid client"),
+                available: record[1].parse().expect("invalid available"),
+                held: record[2].parse().expect("invalid held"),
+                total: record[3].parse().expect("invalid total"),
+                locked: record[4].parse().expect("invalid locked"),
+            }
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn csv_pipeline_produces_expected_balances() {
+    let mut input = NamedTempFile::new().expect("failed to create temp file");
+    input
+        .write_all(INPUT.as_bytes())
+        .expect("failed to write input");
+
+    let mut engine = PaymentsEngineBuilder::new().build(tokio_stream::empty());
+    engine
+        .process_txs_from_file(&[input.path()])
+        .await
+        .expect("failed to process input");
+    let mut report = Vec::new();
+    engine
+        .write_report(&mut report)
+        .expect("failed to write report");
+
+    let row = |client, available, held, total, locked| Row {
+        client,
+        available,
+        held,
+        total,
+        locked,
+    };
+    assert_eq!(
+        parse_report(&report),
+        vec![
+            // Deposit and withdrawal
+            row(1, dec!(7.0), dec!(0.0), dec!(7.0), false),
+            // Open dispute holds the whole deposit
+            row(2, dec!(0.0), dec!(5.0), dec!(5.0), false),
+            // Resolved dispute releases the held funds
+            row(3, dec!(4.0), dec!(0.0), dec!(4.0), false),
+            // Chargeback removes the held funds once and locks the account
+            row(4, dec!(2.0), dec!(0.0), dec!(2.0), true),
+            // Overdrawing withdrawal is ignored
+            row(5, dec!(1.5), dec!(0.0), dec!(1.5), false),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn from_csv_path_reads_the_file_in_the_background() {
+    let mut input = NamedTempFile::new().expect("failed to create temp file");
+    input
+        .write_all(INPUT.as_bytes())
+        .expect("failed to write input");
+
+    let mut engine = PaymentsEngineBuilder::new()
+        .from_csv_path(input.path())
+        .await
+        .expect("failed to open input");
+    engine.process_txs().await.expect("failed to process input");
+    let mut report = Vec::new();
+    engine
+        .write_report(&mut report)
+        .expect("failed to write report");
+    assert_eq!(parse_report(&report).len(), 5);
+
+    assert!(PaymentsEngineBuilder::new()
+        .from_csv_path(input.path().with_extension("missing"))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn process_txs_from_file_reads_the_files_in_order() {
+    let mut first = NamedTempFile::new().expect("failed to create temp file");
+    first
+        .write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,3.0\n")
+        .expect("failed to write input");
+    let mut second = NamedTempFile::new().expect("failed to create temp file");
+    second
+        .write_all(b"type,client,tx,amount\nwithdrawal,1,3,4.0\ndispute,2,2,\n")
+        .expect("failed to write input");
+
+    let mut engine = PaymentsEngineBuilder::new().build(tokio_stream::empty());
+    engine
+        .process_txs_from_file(&[first.path(), second.path()])
+        .await
+        .expect("failed to process input");
+    let mut report = Vec::new();
+    engine
+        .write_report(&mut report)
+        .expect("failed to write report");
+    assert_eq!(
+        parse_report(&report),
+        vec![
+            Row {
+                client: 1,
+                available: dec!(6.0),
+                held: dec!(0.0),
+                total: dec!(6.0),
+                locked: false,
+            },
+            // The dispute in the second file holds the deposit from the first
+            Row {
+                client: 2,
+                available: dec!(0.0),
+                held: dec!(3.0),
+                total: dec!(3.0),
+                locked: false,
+            },
+        ]
+    );
+}
@@ -0,0 +1,110 @@
+use rust_decimal_macros::dec;
+use tokio_stream::Stream;
+
+use payengine::engine::PaymentsEngine;
+use payengine::engine::Tx;
+use payengine::engine::TxInner;
+
+const TX_COUNT: u64 = 1_000_000;
+const CLIENT_COUNT: u64 = 10_000;
+const SHARDS: usize = 16;
+
+fn client_of(tx_id: u64) -> u16 {
+    (tx_id % CLIENT_COUNT + 1) as u16
+}
+
+/// A hundred rounds in which every client makes one transaction of the
+/// same kind: mostly deposits, with withdrawals, transfers between shards,
+/// disputes, resolves and the odd chargeback mixed in.
+fn txs() -> Vec<Tx> {
+    (CLIENT_COUNT..CLIENT_COUNT + TX_COUNT)
+        .map(|tx_id| {
+            let client_id = client_of(tx_id);
+            let earlier = |rounds| Tx {
+                client_id,
+                tx_id: tx_id - rounds * CLIENT_COUNT,
+                inner: TxInner::Dispute,
+            };
+            let inner = match (tx_id / CLIENT_COUNT) % 20 {
+                3 | 7 => TxInner::Withdrawal { amount: dec!(0.75) },
+                // More than most clients have
+                11 => TxInner::Withdrawal { amount: dec!(12.0) },
+                9 | 13 => TxInner::Transfer {
+                    to_client_id: client_of(tx_id * 7 + 3),
+                    amount: dec!(0.5),
+                },
+                // Disputes the withdrawal two rounds before
+                5 => return earlier(2),
+                6 => {
+                    return Tx {
+                        inner: TxInner::Resolve,
+                        ..earlier(3)
+                    }
+                }
+                // Disputes the deposit a round before, then charges back
+                // every seventh client's
+                15 => return earlier(1),
+                16 if client_id.is_multiple_of(7) => {
+                    return Tx {
+                        inner: TxInner::Chargeback,
+                        ..earlier(2)
+                    }
+                }
+                16 => {
+                    return Tx {
+                        inner: TxInner::Resolve,
+                        ..earlier(2)
+                    }
+                }
+                _ => TxInner::Deposit { amount: dec!(1.25) },
+            };
+            Tx {
+                client_id,
+                tx_id,
+                inner,
+            }
+        })
+        .collect()
+}
+
+fn report<T: Stream<Item = Tx> + Unpin>(engine: &PaymentsEngine<T>) -> String {
+    let mut report = Vec::new();
+    engine
+        .write_report(&mut report)
+        .expect("failed to write report");
+    String::from_utf8(report).expect("report is not UTF-8")
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 16)]
+async fn parallel_shards_match_sequential_processing() {
+    let input = txs();
+
+    let mut sequential = PaymentsEngine::new(tokio_stream::iter(input.clone()));
+    sequential
+        .process_txs()
+        .await
+        .expect("failed to process sequentially");
+    let mut parallel = PaymentsEngine::new(tokio_stream::iter(input));
+    parallel
+        .process_txs_parallel(SHARDS)
+        .await
+        .expect("failed to process in parallel");
+
+    let (parallel_summary, sequential_summary) = (
+        parallel.processing_summary(),
+        sequential.processing_summary(),
+    );
+    assert_eq!(
+        parallel_summary.transactions_accepted,
+        sequential_summary.transactions_accepted
+    );
+    assert_eq!(
+        parallel_summary.transactions_ignored,
+        sequential_summary.transactions_ignored
+    );
+    assert!(sequential_summary.transactions_ignored > 0);
+    assert_eq!(parallel.client_count(), CLIENT_COUNT as usize);
+    let parallel_report = report(&parallel);
+    assert!(parallel_report.contains(",true"));
+    assert_eq!(parallel_report, report(&sequential));
+}